@@ -1,36 +1,538 @@
+use crate::config::{expand_path, Config, InvitePolicy};
 use crate::matrix::convert_message_type;
-use futures::{pin_mut, StreamExt};
+use futures::{pin_mut, Stream, StreamExt};
 
 use crate::matrix::*;
 use matrix_sdk::{
+    deserialized_responses::SyncRoomEvent,
+    encryption::verification::{SasVerification, VerificationRequest},
     room::Room as MatrixRoom,
-    ruma::events::{
-        room::{
-            member::{MembershipState, OriginalSyncRoomMemberEvent},
-            message::OriginalSyncRoomMessageEvent,
+    ruma::{
+        events::{
+            fully_read::FullyReadEvent,
+            reaction::OriginalSyncReactionEvent,
+            receipt::SyncReceiptEvent,
+            room::{
+                encrypted::OriginalSyncRoomEncryptedEvent,
+                member::{MembershipChange, MembershipState, OriginalSyncRoomMemberEvent},
+                message::{
+                    MessageFormat, MessageType, OriginalSyncRoomMessageEvent, Relation,
+                    RoomMessageEventContent,
+                },
+                redaction::OriginalSyncRoomRedactionEvent,
+            },
+            typing::SyncTypingEvent,
+            AnySyncMessageLikeEvent, AnySyncRoomEvent, AnySyncStateEvent,
+            RedactedMessageLikeEventContent, RedactedSyncMessageLikeEvent,
+            RoomAccountDataEventType, SyncMessageLikeEvent, SyncStateEvent,
         },
-        AnySyncMessageLikeEvent, AnySyncRoomEvent, SyncMessageLikeEvent,
+        receipt::ReceiptType,
+        EventId, MilliSecondsSinceUnixEpoch, RoomId, TransactionId, UserId,
     },
     Client, RoomType,
 };
 
+use tokio::sync::mpsc::{self, Receiver, Sender};
+use tui::text::Text;
 use tui::widgets::ListState;
 
 use chrono::offset::Utc;
 use chrono::DateTime;
 
-use std::time::SystemTime;
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::PathBuf,
+    time::{Duration, Instant, SystemTime},
+};
 use url::Url;
 
+/// Number of timeline events loaded per room on startup and per page of older history
+const HISTORY_LIMIT: usize = 100;
+/// Maximum number of pages of already loaded events skipped to find the oldest loaded event
+const HISTORY_SKIP_PAGES: usize = 10;
+
+/// Shortcodes which can be used for reactions and their emoji
+const REACTION_SHORTCODES: [(&str, &str); 40] = [
+    ("thumbsup", "👍"),
+    ("+1", "👍"),
+    ("thumbsdown", "👎"),
+    ("-1", "👎"),
+    ("heart", "❤️"),
+    ("joy", "😂"),
+    ("rofl", "🤣"),
+    ("smile", "😄"),
+    ("smiley", "😃"),
+    ("grin", "😁"),
+    ("laughing", "😆"),
+    ("sweat_smile", "😅"),
+    ("wink", "😉"),
+    ("slightly_smiling_face", "🙂"),
+    ("upside_down_face", "🙃"),
+    ("heart_eyes", "😍"),
+    ("thinking", "🤔"),
+    ("confused", "😕"),
+    ("cry", "😢"),
+    ("sob", "😭"),
+    ("scream", "😱"),
+    ("see_no_evil", "🙈"),
+    ("facepalm", "🤦"),
+    ("shrug", "🤷"),
+    ("tada", "🎉"),
+    ("partying_face", "🥳"),
+    ("eyes", "👀"),
+    ("rocket", "🚀"),
+    ("fire", "🔥"),
+    ("100", "💯"),
+    ("star", "⭐"),
+    ("sparkles", "✨"),
+    ("clap", "👏"),
+    ("raised_hands", "🙌"),
+    ("pray", "🙏"),
+    ("muscle", "💪"),
+    ("ok_hand", "👌"),
+    ("wave", "👋"),
+    ("white_check_mark", "✅"),
+    ("x", "❌"),
+];
+
+/// Prefix of messages which are sent as plain text even if markdown is enabled
+const PLAIN_PREFIX: &str = "/plain ";
+
+/// Prefix of messages which are sent as emote, shown like "* alice waves"
+const EMOTE_PREFIX: &str = "/me ";
+
+/// Prefix of the command which uploads a file, the rest of the input is the path
+const UPLOAD_PREFIX: &str = "/upload ";
+
+/// Number of file names shown when a path completes to several files
+const MAX_COMPLETIONS: usize = 10;
+
+/// Number of characters of a message shown when it is quoted
+const EXCERPT_LENGTH: usize = 50;
+
+/// Time after which a running typing notice is sent again, before the homeserver lets it expire
+const TYPING_NOTICE_RESEND: Duration = Duration::from_secs(3);
+
 #[derive(Debug, PartialEq, Eq)]
 enum MessageViewMode {
     Follow,
     Scroll,
 }
 
+/// Loading state of the older history of a room
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HistoryState {
+    /// Older events can be loaded
+    Idle,
+    /// Older events are being loaded
+    Loading,
+    /// The creation of the room was reached
+    Complete,
+    /// The oldest loaded event was not found, no more events are loaded
+    Failed,
+}
+
+/// Local send state of a timeline item
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SendState {
+    /// Sent by this client, but not acknowledged by the homeserver yet
+    Sending,
+    /// Acknowledged by or received from the homeserver
+    Sent,
+    /// The homeserver could not be reached or rejected the message
+    Failed,
+}
+
+/// The typed content of a timeline item
+#[derive(Debug, Clone)]
+pub enum TimelineContent {
+    /// A room message
+    Message(RoomMessageEventContent),
+    /// An encrypted message which could not be decrypted, kept to decrypt it again with new keys
+    UnableToDecrypt(Box<OriginalSyncRoomEncryptedEvent>),
+    /// A deleted message
+    Redacted,
+    /// A change of the room state like a join or a new topic, shown as system line
+    State,
+}
+
+/// The reactions to a message with the same key
+#[derive(Debug, Clone)]
+pub struct Reaction {
+    /// The reaction, usually an emoji
+    pub key: String,
+    /// The user id of the sender and the event id of each reaction
+    pub senders: Vec<(String, String)>,
+}
+
+impl Reaction {
+    /// Returns the event id of the reaction of a user
+    /// # Arguments
+    /// * `user_id` - The user id of the sender
+    pub fn event_id_of(&self, user_id: &str) -> Option<&str> {
+        self.senders
+            .iter()
+            .find(|(sender, _)| sender == user_id)
+            .map(|(_, event_id)| event_id.as_str())
+    }
+}
+
+/// A message in the timeline of a room
+#[derive(Debug, Clone)]
+pub struct TimelineItem {
+    /// The event id, None until the homeserver acknowledged a sent message
+    pub event_id: Option<String>,
+    /// The transaction id of messages sent by this client
+    pub transaction_id: Option<String>,
+    /// The user id of the sender
+    pub sender: String,
+    /// The display name of the sender
+    pub sender_name: String,
+    /// The time the homeserver received the message
+    pub timestamp: DateTime<Utc>,
+    pub content: TimelineContent,
+    /// The content as readable text
+    pub body: String,
+    /// The event id of the message this message replies to
+    pub in_reply_to: Option<String>,
+    /// The event id of the message this message is an edit of
+    pub replaces: Option<String>,
+    /// The event id of the root of the thread the message belongs to
+    pub thread_root: Option<String>,
+    /// Whether the message was edited
+    pub edited: bool,
+    /// The reactions to the message, in the order they were first used
+    pub reactions: Vec<Reaction>,
+    pub send_state: SendState,
+    /// The rendered body with the width it was wrapped at, cleared when the body changes
+    pub rendered: Option<(usize, Text<'static>)>,
+}
+
+impl TimelineItem {
+    /// Create a timeline item from a room message
+    /// # Arguments
+    /// * `event` - The message event
+    /// * `sender_name` - The display name of the sender
+    /// * `homeserver_url` - The homeserver url, used to link media
+    pub fn from_message_event(
+        event: OriginalSyncRoomMessageEvent,
+        sender_name: String,
+        homeserver_url: Url,
+    ) -> TimelineItem {
+        let (content, in_reply_to, replaces, thread_root) = match event.content.relates_to.clone() {
+            Some(Relation::Reply { in_reply_to }) => (
+                event.content,
+                Some(in_reply_to.event_id.to_string()),
+                None,
+                None,
+            ),
+            // Edits carry the new content of the edited message
+            Some(Relation::Replacement(replacement)) => (
+                *replacement.new_content,
+                None,
+                Some(replacement.event_id.to_string()),
+                None,
+            ),
+            Some(Relation::Thread(thread)) => {
+                (event.content, None, None, Some(thread.event_id.to_string()))
+            }
+            _ => (event.content, None, None, None),
+        };
+        let mut body = convert_message_type(content.msgtype.clone(), homeserver_url);
+        // The quoted message is shown above the body instead
+        if in_reply_to.is_some() {
+            body = strip_reply_fallback(&body);
+        }
+        TimelineItem {
+            event_id: Some(event.event_id.to_string()),
+            transaction_id: event.unsigned.transaction_id.map(|id| id.to_string()),
+            sender: event.sender.to_string(),
+            sender_name,
+            timestamp: to_date_time(event.origin_server_ts),
+            content: TimelineContent::Message(content),
+            body,
+            in_reply_to,
+            replaces,
+            thread_root,
+            edited: false,
+            reactions: Vec::new(),
+            send_state: SendState::Sent,
+            rendered: None,
+        }
+    }
+
+    /// Create a placeholder for a message which could not be decrypted
+    /// # Arguments
+    /// * `event` - The encrypted event
+    /// * `sender_name` - The display name of the sender
+    pub fn from_encrypted_event(
+        event: OriginalSyncRoomEncryptedEvent,
+        sender_name: String,
+    ) -> TimelineItem {
+        TimelineItem {
+            event_id: Some(event.event_id.to_string()),
+            transaction_id: None,
+            sender: event.sender.to_string(),
+            sender_name,
+            timestamp: to_date_time(event.origin_server_ts),
+            content: TimelineContent::UnableToDecrypt(Box::new(event)),
+            body: UNABLE_TO_DECRYPT.to_string(),
+            in_reply_to: None,
+            replaces: None,
+            thread_root: None,
+            edited: false,
+            reactions: Vec::new(),
+            send_state: SendState::Sent,
+            rendered: None,
+        }
+    }
+
+    /// Create a placeholder for a deleted message
+    /// # Arguments
+    /// * `event` - The redacted event
+    /// * `members` - The members of the room, used to look up the display names
+    pub fn from_redacted_event<C: RedactedMessageLikeEventContent>(
+        event: RedactedSyncMessageLikeEvent<C>,
+        members: &ScrollableMemberList,
+    ) -> TimelineItem {
+        let body = match &event.unsigned.redacted_because {
+            Some(redaction) => redacted_body(
+                &members.display_name(redaction.sender.as_str()),
+                redaction.content.reason.as_deref(),
+            ),
+            None => redacted_body("unknown", None),
+        };
+        TimelineItem {
+            event_id: Some(event.event_id.to_string()),
+            transaction_id: None,
+            sender: event.sender.to_string(),
+            sender_name: members.display_name(event.sender.as_str()),
+            timestamp: to_date_time(event.origin_server_ts),
+            content: TimelineContent::Redacted,
+            body,
+            in_reply_to: None,
+            replaces: None,
+            thread_root: None,
+            edited: false,
+            reactions: Vec::new(),
+            send_state: SendState::Sent,
+            rendered: None,
+        }
+    }
+
+    /// Create a system line for a change of the room state
+    /// # Arguments
+    /// * `event_id` - The event id of the state event
+    /// * `sender` - The user id of the user who changed the state
+    /// * `sender_name` - The display name of the sender
+    /// * `timestamp` - The time the homeserver received the event
+    /// * `body` - The description of the change
+    pub fn from_state_change(
+        event_id: &EventId,
+        sender: &UserId,
+        sender_name: String,
+        timestamp: MilliSecondsSinceUnixEpoch,
+        body: String,
+    ) -> TimelineItem {
+        TimelineItem {
+            event_id: Some(event_id.to_string()),
+            transaction_id: None,
+            sender: sender.to_string(),
+            sender_name,
+            timestamp: to_date_time(timestamp),
+            content: TimelineContent::State,
+            body,
+            in_reply_to: None,
+            replaces: None,
+            thread_root: None,
+            edited: false,
+            reactions: Vec::new(),
+            send_state: SendState::Sent,
+            rendered: None,
+        }
+    }
+
+    /// Returns the file attached to the message
+    pub fn attachment(&self) -> Option<Attachment> {
+        match &self.content {
+            TimelineContent::Message(content) => Attachment::from_message_type(&content.msgtype),
+            TimelineContent::UnableToDecrypt(_)
+            | TimelineContent::Redacted
+            | TimelineContent::State => None,
+        }
+    }
+
+    /// Returns the type of a message, like text, emote or notice
+    pub fn msgtype(&self) -> Option<&MessageType> {
+        match &self.content {
+            TimelineContent::Message(content) => Some(&content.msgtype),
+            _ => None,
+        }
+    }
+
+    /// Returns the HTML formatted body of a text, notice or emote message
+    pub fn formatted_body(&self) -> Option<&str> {
+        let content = match &self.content {
+            TimelineContent::Message(content) => content,
+            _ => return None,
+        };
+        let formatted = match &content.msgtype {
+            MessageType::Text(content) => content.formatted.as_ref(),
+            MessageType::Notice(content) => content.formatted.as_ref(),
+            MessageType::Emote(content) => content.formatted.as_ref(),
+            _ => None,
+        };
+        match formatted {
+            Some(formatted) if formatted.format == MessageFormat::Html => Some(&formatted.body),
+            _ => None,
+        }
+    }
+
+    /// Returns the beginning of the first line of the body
+    pub fn excerpt(&self) -> String {
+        let line = self.body.lines().next().unwrap_or("");
+        if line.chars().count() > EXCERPT_LENGTH || self.body.lines().nth(1).is_some() {
+            let excerpt: String = line.chars().take(EXCERPT_LENGTH).collect();
+            format!("{}...", excerpt)
+        } else {
+            line.to_string()
+        }
+    }
+}
+
+/// Returns the emoji of a reaction typed into the input.
+/// Shortcodes like ":thumbsup:" are replaced with their emoji, other text is used as it is.
+/// # Arguments
+/// * `input` - The emoji or shortcode
+fn reaction_key(input: &str) -> Option<String> {
+    let input = input.trim();
+    if input.is_empty() {
+        return None;
+    }
+    match input.strip_prefix(':').and_then(|s| s.strip_suffix(':')) {
+        Some(shortcode) => REACTION_SHORTCODES
+            .iter()
+            .find(|(name, _)| *name == shortcode)
+            .map(|(_, emoji)| emoji.to_string()),
+        None => Some(input.to_string()),
+    }
+}
+
+/// Returns the rest of a command line after its first words.
+/// Used for passwords and passphrases, which may contain spaces.
+/// # Arguments
+/// * `command` - The command line
+/// * `words` - The number of words before the rest, including the command
+/// # Returns
+/// * `Option<&str>` - The rest without the leading spaces, None if it is empty
+fn rest_of_line(command: &str, words: usize) -> Option<&str> {
+    let mut rest = command;
+    for _ in 0..words {
+        rest = rest.trim_start();
+        rest = &rest[rest.find(char::is_whitespace)?..];
+    }
+    match rest.trim_start() {
+        "" => None,
+        rest => Some(rest),
+    }
+}
+
+/// Returns the placeholder shown instead of a deleted message
+/// # Arguments
+/// * `redacted_by` - The display name of the user who deleted the message
+/// * `reason` - The reason given for the deletion
+fn redacted_body(redacted_by: &str, reason: Option<&str>) -> String {
+    match reason {
+        Some(reason) => format!("** Message deleted by {}: {} **", redacted_by, reason),
+        None => format!("** Message deleted by {} **", redacted_by),
+    }
+}
+
+/// Returns the description of a membership change, None for changes which are not shown
+/// # Arguments
+/// * `event` - The member event
+/// * `members` - The members of the room, used to look up the display name of the sender
+fn membership_body(
+    event: &OriginalSyncRoomMemberEvent,
+    members: &ScrollableMemberList,
+) -> Option<String> {
+    let sender = members.display_name(event.sender.as_str());
+    let target = match (
+        &event.content.displayname,
+        event
+            .unsigned
+            .prev_content
+            .as_ref()
+            .and_then(|c| c.displayname.as_ref()),
+    ) {
+        (Some(name), _) | (None, Some(name)) => name.to_string(),
+        (None, None) => event.state_key.to_string(),
+    };
+    let reason = match &event.content.reason {
+        Some(reason) => format!(": {}", reason),
+        None => String::new(),
+    };
+    let body = match event.membership_change() {
+        MembershipChange::Joined | MembershipChange::InvitationAccepted => {
+            format!("{} joined the room", target)
+        }
+        MembershipChange::Left => format!("{} left the room{}", target, reason),
+        MembershipChange::Invited => format!("{} invited {}", sender, target),
+        MembershipChange::InvitationRejected => format!("{} rejected the invitation", target),
+        MembershipChange::InvitationRevoked => {
+            format!("{} revoked the invitation of {}", sender, target)
+        }
+        MembershipChange::Kicked => format!("{} was kicked by {}{}", target, sender, reason),
+        MembershipChange::Banned => format!("{} was banned by {}{}", target, sender, reason),
+        MembershipChange::KickedAndBanned => {
+            format!("{} was kicked and banned by {}{}", target, sender, reason)
+        }
+        MembershipChange::Unbanned => format!("{} was unbanned by {}", target, sender),
+        MembershipChange::ProfileChanged {
+            displayname_change: Some(change),
+            ..
+        } => match (change.old, change.new) {
+            (Some(old), Some(new)) => format!("{} changed their name to {}", old, new),
+            (None, Some(new)) => format!("{} set their name to {}", event.state_key, new),
+            (Some(old), None) => format!("{} removed their name", old),
+            (None, None) => return None,
+        },
+        _ => return None,
+    };
+    Some(body)
+}
+
+/// Converts a matrix timestamp, invalid timestamps are mapped to the unix epoch
+/// # Arguments
+/// * `timestamp` - The timestamp to convert
+fn to_date_time(timestamp: MilliSecondsSinceUnixEpoch) -> DateTime<Utc> {
+    match timestamp.to_system_time() {
+        Some(time) => time.into(),
+        None => SystemTime::UNIX_EPOCH.into(),
+    }
+}
+
+/// Formats a file size in bytes with a binary unit, e.g. "1.5 MiB"
+/// # Arguments
+/// * `bytes` - The size in bytes
+fn format_size(bytes: u64) -> String {
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let units = ["KiB", "MiB", "GiB"];
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < units.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, units[unit])
+}
+
 pub struct ScrollableMessageList {
     pub state: ListState,
-    pub messages: Vec<(String, String, String)>,
+    pub messages: Vec<TimelineItem>,
     mode: MessageViewMode,
 }
 
@@ -44,32 +546,206 @@ impl ScrollableMessageList {
         }
     }
 
-    /// Create a new ScrollableMessageList with the given messages.
-    pub fn with_messages(messages: Vec<(String, String, String)>) -> ScrollableMessageList {
-        let mut list = ScrollableMessageList {
-            state: ListState::default(),
-            messages,
-            mode: MessageViewMode::Follow,
-        };
-        list.state
-            .select(Some(list.messages.len().saturating_sub(1)));
-        list
-    }
-
     /// Add a message to the list.
+    /// The local echo of a message sent by this client is replaced,
+    /// messages which are already in the list are ignored.
     /// If Follow mode is active, the cursor will be moved to the newest message
     /// # Arguments
-    /// * `time` - The time the message was sent.
-    /// * `sender` - The sender of the message.
     /// * `message` - The message.
-    pub fn add_message(&mut self, time: String, sender: String, message: String) {
-        self.messages.push((time, sender, message));
+    pub fn add_message(&mut self, message: TimelineItem) {
+        if let Some(transaction_id) = &message.transaction_id {
+            if let Some(m) = self
+                .messages
+                .iter_mut()
+                .find(|m| m.transaction_id.as_ref() == Some(transaction_id))
+            {
+                *m = message;
+                return;
+            }
+        }
+        if message.event_id.is_some()
+            && self.messages.iter().any(|m| m.event_id == message.event_id)
+        {
+            return;
+        }
+
+        self.messages.push(message);
 
         if self.mode == MessageViewMode::Follow {
             self.state.select(Some(self.messages.len() - 1));
         }
     }
 
+    /// Add a reaction to the message it reacts to.
+    /// Reactions to unknown messages and reactions which are already counted are ignored.
+    /// # Arguments
+    /// * `event_id` - The event id of the message
+    /// * `key` - The reaction, usually an emoji
+    /// * `sender` - The user id of the sender of the reaction
+    /// * `reaction_id` - The event id of the reaction
+    pub fn add_reaction(&mut self, event_id: &str, key: &str, sender: &str, reaction_id: &str) {
+        let message = match self
+            .messages
+            .iter_mut()
+            .find(|m| m.event_id.as_deref() == Some(event_id))
+        {
+            Some(message) => message,
+            None => return,
+        };
+        if message
+            .reactions
+            .iter()
+            .any(|r| r.senders.iter().any(|(_, id)| id == reaction_id))
+        {
+            return;
+        }
+        match message.reactions.iter_mut().find(|r| r.key == key) {
+            Some(reaction) => reaction
+                .senders
+                .push((sender.to_string(), reaction_id.to_string())),
+            None => message.reactions.push(Reaction {
+                key: key.to_string(),
+                senders: vec![(sender.to_string(), reaction_id.to_string())],
+            }),
+        }
+    }
+
+    /// Replace the content of an edited message.
+    /// Edits of other users than the sender and of unknown messages are ignored.
+    /// # Arguments
+    /// * `event_id` - The event id of the edited message
+    /// * `sender` - The user id of the editor
+    /// * `content` - The new content
+    /// * `body` - The new content as readable text
+    pub fn apply_edit(
+        &mut self,
+        event_id: &str,
+        sender: &str,
+        content: TimelineContent,
+        body: String,
+    ) {
+        if let Some(message) = self
+            .messages
+            .iter_mut()
+            .find(|m| m.event_id.as_deref() == Some(event_id) && m.sender == sender)
+        {
+            message.content = content;
+            message.body = body;
+            message.edited = true;
+            message.rendered = None;
+        }
+    }
+
+    /// Restore a message whose edit could not be sent, unless it changed again meanwhile
+    /// # Arguments
+    /// * `original` - The message before the edit
+    /// * `body` - The body of the edit
+    pub fn revert_edit(&mut self, original: &TimelineItem, body: &str) {
+        if let Some(message) = self
+            .messages
+            .iter_mut()
+            .find(|m| m.event_id == original.event_id && m.body == body)
+        {
+            message.content = original.content.clone();
+            message.body = original.body.clone();
+            message.edited = original.edited;
+            message.rendered = None;
+        }
+    }
+
+    /// Replace a deleted message with a placeholder or remove a deleted reaction
+    /// # Arguments
+    /// * `event_id` - The event id of the deleted message
+    /// * `body` - The placeholder text
+    pub fn apply_redaction(&mut self, event_id: &str, body: String) {
+        if let Some(message) = self
+            .messages
+            .iter_mut()
+            .find(|m| m.event_id.as_deref() == Some(event_id))
+        {
+            message.content = TimelineContent::Redacted;
+            message.body = body;
+            message.edited = false;
+            message.reactions.clear();
+            message.rendered = None;
+            return;
+        }
+
+        // The redacted event may be a reaction
+        for message in self.messages.iter_mut() {
+            for reaction in message.reactions.iter_mut() {
+                reaction.senders.retain(|(_, id)| id != event_id);
+            }
+            message.reactions.retain(|r| !r.senders.is_empty());
+        }
+    }
+
+    /// Update the send state of a message sent by this client
+    /// # Arguments
+    /// * `transaction_id` - The transaction id of the message
+    /// * `event_id` - The event id returned by the homeserver, None if sending failed
+    pub fn update_send_state(&mut self, transaction_id: &str, event_id: Option<String>) {
+        let message = match self
+            .messages
+            .iter_mut()
+            .find(|m| m.transaction_id.as_deref() == Some(transaction_id))
+        {
+            Some(message) => message,
+            None => return,
+        };
+        // The message may already have been received by the sync
+        if message.send_state != SendState::Sending {
+            return;
+        }
+        match event_id {
+            Some(event_id) => {
+                message.event_id = Some(event_id);
+                message.send_state = SendState::Sent;
+            }
+            None => message.send_state = SendState::Failed,
+        }
+    }
+
+    /// Replace the placeholder of a message which could not be decrypted with the decrypted message.
+    /// The reactions to the placeholder are kept.
+    /// # Arguments
+    /// * `message` - The decrypted message
+    /// # Returns
+    /// * `bool` - Whether the placeholder was found
+    pub fn replace_placeholder(&mut self, message: &TimelineItem) -> bool {
+        let placeholder = match self.messages.iter_mut().find(|m| {
+            m.event_id == message.event_id
+                && matches!(m.content, TimelineContent::UnableToDecrypt(_))
+        }) {
+            Some(placeholder) => placeholder,
+            None => return false,
+        };
+        let reactions = std::mem::take(&mut placeholder.reactions);
+        *placeholder = message.clone();
+        placeholder.reactions = reactions;
+        true
+    }
+
+    /// Remove the placeholder of a message which could not be decrypted.
+    /// The selection stays on the same message if possible.
+    /// # Arguments
+    /// * `event_id` - The event id of the message
+    pub fn remove_placeholder(&mut self, event_id: &str) {
+        let index = match self.messages.iter().position(|m| {
+            m.event_id.as_deref() == Some(event_id)
+                && matches!(m.content, TimelineContent::UnableToDecrypt(_))
+        }) {
+            Some(index) => index,
+            None => return,
+        };
+        self.messages.remove(index);
+        match self.state.selected() {
+            Some(_) if self.messages.is_empty() => self.state.select(None),
+            Some(i) if i > index || i == self.messages.len() => self.state.select(Some(i - 1)),
+            _ => {}
+        }
+    }
+
     /// Change the selected message to the next one
     pub fn next_message(&mut self) {
         if self.messages.is_empty() {
@@ -108,6 +784,24 @@ impl ScrollableMessageList {
         };
         self.state.select(Some(i));
     }
+
+    /// Returns whether the list follows the newest message
+    pub fn is_following(&self) -> bool {
+        self.mode == MessageViewMode::Follow
+    }
+
+    /// Append the already loaded messages to the older messages added to the emptied list.
+    /// The previously selected message stays selected.
+    /// # Arguments
+    /// * `newer` - The already loaded messages
+    /// * `selected` - The index of the selected message in the loaded messages
+    fn restore_newer(&mut self, newer: Vec<TimelineItem>, selected: Option<usize>) {
+        let added = self.messages.len();
+        self.messages.extend(newer);
+        if let Some(i) = selected {
+            self.state.select(Some(i + added));
+        }
+    }
 }
 
 pub struct ScrollableMemberList {
@@ -127,6 +821,16 @@ impl ScrollableMemberList {
         }
     }
 
+    /// Returns the display name of a member, or the user id if it is unknown
+    /// # Arguments
+    /// * `user_id` - The user id of the member
+    pub fn display_name(&self, user_id: &str) -> String {
+        match self.members.iter().find(|m| m.1 == user_id) {
+            Some((name, _)) => name.to_string(),
+            None => user_id.to_string(),
+        }
+    }
+
     /// Change the selected member to the next one
     pub fn next_member(&mut self) {
         if self.members.is_empty() {
@@ -164,16 +868,160 @@ impl ScrollableMemberList {
     }
 }
 
+/// Reads up to HISTORY_LIMIT events of the room history, from newest to oldest.
+/// Events which can not be parsed are skipped.
+/// # Arguments
+/// * `timeline` - The timeline of the room, continues after the events read from it before.
+/// * `before` - The event id of the oldest loaded event if the timeline starts at the newest
+/// event, the events up to it are skipped. At most HISTORY_SKIP_PAGES pages are skipped.
+/// # Returns
+/// * `(Vec<AnySyncRoomEvent>, Option<String>, HistoryState)` - The events, the event id of the
+/// oldest read event and whether the creation of the room was reached or the oldest loaded
+/// event was not found
+pub async fn read_history<S>(
+    timeline: &mut S,
+    before: Option<&str>,
+) -> (Vec<AnySyncRoomEvent>, Option<String>, HistoryState)
+where
+    S: Stream<Item = matrix_sdk::Result<SyncRoomEvent>> + Unpin,
+{
+    let mut events: Vec<AnySyncRoomEvent> = Vec::new();
+    let mut oldest_event = None;
+    let mut skipping = before.is_some();
+    let mut skipped = 0;
+
+    while events.len() < HISTORY_LIMIT {
+        let event = match timeline.next().await {
+            Some(Ok(event)) => event,
+            Some(Err(_)) => return (events, oldest_event, HistoryState::Idle),
+            // The oldest loaded event was not found before the end
+            None if skipping => return (events, oldest_event, HistoryState::Failed),
+            None => return (events, oldest_event, HistoryState::Complete),
+        };
+        // The timeline starts at the newest event, skip the events which are already loaded
+        if skipping {
+            skipped += 1;
+            if skipped > HISTORY_SKIP_PAGES * HISTORY_LIMIT {
+                return (events, oldest_event, HistoryState::Failed);
+            }
+        }
+        let event_id = match event.event.get_field::<String>("event_id") {
+            Ok(Some(event_id)) => event_id,
+            _ => continue,
+        };
+        if skipping {
+            skipping = before != Some(event_id.as_str());
+            continue;
+        }
+        oldest_event = Some(event_id);
+        if let Ok(event) = event.event.deserialize() {
+            let created = matches!(
+                event,
+                AnySyncRoomEvent::State(AnySyncStateEvent::RoomCreate(_))
+            );
+            events.push(event);
+            if created {
+                return (events, oldest_event, HistoryState::Complete);
+            }
+        }
+    }
+    (events, oldest_event, HistoryState::Idle)
+}
+
+/// Reads a page of older history of a room whenever one is requested.
+/// The timeline is kept between the pages, so each page continues where the last one ended.
+/// # Arguments
+/// * `room` - The room.
+/// * `requests` - The event ids of the oldest loaded event, one per requested page.
+/// Only the first is used, to skip the events which were loaded before.
+/// * `tx` - The channel to send the pages to.
+async fn serve_history(
+    room: MatrixRoom,
+    mut requests: Receiver<Option<String>>,
+    tx: Sender<(String, Vec<AnySyncRoomEvent>, Option<String>, HistoryState)>,
+) {
+    let room_id = room.room_id().to_string();
+    let mut before = match requests.recv().await {
+        Some(before) => before,
+        None => return,
+    };
+    let timeline = match room.timeline_backward().await {
+        Ok(timeline) => timeline,
+        Err(_) => {
+            if (tx
+                .send((room_id, Vec::new(), None, HistoryState::Idle))
+                .await)
+                .is_ok()
+            {};
+            return;
+        }
+    };
+    pin_mut!(timeline);
+    loop {
+        let (events, oldest_event, history) = read_history(&mut timeline, before.as_deref()).await;
+        if tx
+            .send((room_id.clone(), events, oldest_event, history))
+            .await
+            .is_err()
+            || history != HistoryState::Idle
+        {
+            return;
+        }
+        before = match requests.recv().await {
+            Some(_) => None,
+            None => return,
+        };
+    }
+}
+
+/// Decrypts messages which could not be decrypted before and sends the decrypted ones to the UI
+/// # Arguments
+/// * `client` - The client with the new room keys.
+/// * `rooms` - The room ids and the messages which could not be decrypted.
+/// * `tx` - The channel to send the decrypted events to.
+async fn send_decrypted(
+    client: &Client,
+    rooms: Vec<(String, Vec<OriginalSyncRoomEncryptedEvent>)>,
+    tx: &Sender<(String, Vec<AnySyncRoomEvent>)>,
+) {
+    for (room_id, events) in rooms {
+        let events = client.decrypt_events(&room_id, events).await;
+        if !events.is_empty() && (tx.send((room_id, events)).await).is_ok() {};
+    }
+}
+
 pub struct Room {
     pub name: String,
     pub id: String,
     pub messages: ScrollableMessageList,
+    /// The replies of each thread by the event id of the thread root
+    pub threads: HashMap<String, ScrollableMessageList>,
     pub members: ScrollableMemberList,
+    pub history: HistoryState,
+    /// The user ids of the other members which are typing
+    pub typing: Vec<String>,
+    /// The event id of the last read event by user id
+    pub receipts: HashMap<String, String>,
+    /// Whether the receipts saved in the store were loaded, which happens when the room is shown
+    receipts_loaded: bool,
+    /// The event id of the read marker when the room was opened, new messages follow it
+    pub read_marker: Option<String>,
+    /// The event id of the current read marker
+    fully_read: Option<String>,
+    /// Number of unread messages which notify
+    pub notifications: u64,
+    /// Number of unread messages which mention the user
+    pub highlights: u64,
+    /// The event id of the oldest loaded event, older history is loaded before it
+    oldest_event: Option<String>,
+    /// Requests a page of older history from the task which keeps the timeline of the room
+    history_requests: Option<Sender<Option<String>>>,
 }
 
 impl Room {
     /// Create a new room with the given name and id.
-    /// Gets past members and past messages from the room.
+    /// Gets past members and the last messages from the room.
+    /// Both are read from the local store if they were loaded before.
     ///
     /// # Arguments
     /// * `name` - The room to create.
@@ -189,116 +1037,433 @@ impl Room {
             Err(_) => Vec::new(),
         };
 
-        let member_names = members
-            .into_iter()
-            .map(|member| match member.display_name() {
-                Some(name) => (name.to_string(), member.user_id().to_string()),
-                None => (member.user_id().to_string(), member.user_id().to_string()),
-            })
-            .collect::<Vec<(String, String)>>();
+        let fully_read = match room.account_data(RoomAccountDataEventType::FullyRead).await {
+            Ok(Some(event)) => match event.deserialize_as::<FullyReadEvent>() {
+                Ok(event) => Some(event.content.event_id.to_string()),
+                Err(_) => None,
+            },
+            _ => None,
+        };
+
+        let unread = room.unread_notification_counts();
+
+        let members = ScrollableMemberList::with_members(
+            members
+                .into_iter()
+                .map(|member| match member.display_name() {
+                    Some(name) => (name.to_string(), member.user_id().to_string()),
+                    None => (member.user_id().to_string(), member.user_id().to_string()),
+                })
+                .collect::<Vec<(String, String)>>(),
+        );
 
         //Get old message
-        match room.timeline_backward().await {
+        let (events, oldest_event, history) = match room.timeline_backward().await {
             Ok(timeline) => {
-                let mut messages: Vec<(String, String, String)> = Vec::new();
-
                 pin_mut!(timeline);
-                while let Some(event) = timeline.next().await {
-                    let event = match event {
-                        Ok(event) => event,
-                        Err(_) => break,
-                    };
-                    let event = match event.event.deserialize() {
-                        Ok(event) => event,
-                        Err(_) => break,
-                    };
-                    if let AnySyncRoomEvent::MessageLike(AnySyncMessageLikeEvent::RoomMessage(
-                        SyncMessageLikeEvent::Original(event),
-                    )) = event
-                    {
-                        let system_time = match event.origin_server_ts.to_system_time() {
-                            Some(time) => time,
-                            None => SystemTime::UNIX_EPOCH,
-                        };
-                        let sender = event.sender.to_string();
-                        let date_time: DateTime<Utc> = system_time.into();
-
-                        messages.push((
-                            date_time.format("%d/%m/%Y %T").to_string(),
-                            sender,
-                            (convert_message_type(event.content.msgtype, homeserver_url.clone())
-                                .to_string())
-                            .to_string(),
-                        ));
-                    }
-                }
-                messages.reverse();
-                Room {
-                    name,
-                    id: room.room_id().to_string(),
-                    messages: ScrollableMessageList::with_messages(messages),
-                    members: ScrollableMemberList::with_members(member_names),
-                }
+                read_history(&mut timeline, None).await
             }
-            Err(_) => Room {
-                name,
-                id: room.room_id().to_string(),
-                messages: ScrollableMessageList::new(),
-                members: ScrollableMemberList::with_members(member_names),
-            },
+            Err(_) => (Vec::new(), None, HistoryState::Idle),
+        };
+
+        let mut room = Room {
+            name,
+            id: room.room_id().to_string(),
+            messages: ScrollableMessageList::new(),
+            threads: HashMap::new(),
+            members,
+            history,
+            typing: Vec::new(),
+            receipts: HashMap::new(),
+            receipts_loaded: false,
+            read_marker: fully_read.clone(),
+            fully_read,
+            notifications: unread.notification_count,
+            highlights: unread.highlight_count,
+            oldest_event,
+            history_requests: None,
+        };
+        // Add the events from oldest to newest, so edits and reactions find their message
+        for event in events.into_iter().rev() {
+            room.add_event(event, &homeserver_url);
         }
+        room
     }
-}
 
-/// Scrollable list of rooms
-pub struct ScrollableRoomList {
-    pub state: ListState,
-    pub rooms: Vec<Room>,
-}
+    /// Add older events of the room history before the loaded messages.
+    /// The selected messages stay selected.
+    /// # Arguments
+    /// * `events` - The events from newest to oldest.
+    /// * `oldest_event` - The event id of the oldest read event.
+    /// * `history` - Whether more events can be loaded.
+    /// * `homeserver_url` - The homeserver url.
+    pub fn add_history(
+        &mut self,
+        events: Vec<AnySyncRoomEvent>,
+        oldest_event: Option<String>,
+        history: HistoryState,
+        homeserver_url: &Url,
+    ) {
+        // The older events are added to emptied timelines first,
+        // so edits and reactions among them find their message
+        let mut newer = vec![(
+            None,
+            std::mem::take(&mut self.messages.messages),
+            self.messages.state.selected(),
+        )];
+        for (root, thread) in self.threads.iter_mut() {
+            newer.push((
+                Some(root.to_string()),
+                std::mem::take(&mut thread.messages),
+                thread.state.selected(),
+            ));
+        }
+        for event in events.into_iter().rev() {
+            self.add_event(event, homeserver_url);
+        }
+        for (root, messages, selected) in newer {
+            let timeline = match root {
+                Some(root) => self
+                    .threads
+                    .entry(root)
+                    .or_insert_with(ScrollableMessageList::new),
+                None => &mut self.messages,
+            };
+            timeline.restore_newer(messages, selected);
+        }
 
-impl ScrollableRoomList {
-    /// Create a new room list
-    pub fn new() -> ScrollableRoomList {
-        ScrollableRoomList {
-            state: ListState::default(),
-            rooms: Vec::new(),
+        if oldest_event.is_some() {
+            self.oldest_event = oldest_event;
         }
+        self.history = history;
     }
 
-    /// Add a new room to the list
-    ///
+    /// Add an event of the room history to the timeline.
+    /// Events which are not shown in the timeline are ignored.
     /// # Arguments
-    /// * `room` - The room to add
-    /// * `homeserver_url` - The homeserver url
-    pub async fn add_room(&mut self, room: MatrixRoom, homeserver_url: Url) {
-        let room = Room::new(room, homeserver_url).await;
-        self.rooms.push(room);
+    /// * `event` - The event.
+    /// * `homeserver_url` - The homeserver url.
+    fn add_event(&mut self, event: AnySyncRoomEvent, homeserver_url: &Url) {
+        match event {
+            AnySyncRoomEvent::MessageLike(AnySyncMessageLikeEvent::RoomMessage(
+                SyncMessageLikeEvent::Original(event),
+            )) => {
+                let sender_name = self.members.display_name(event.sender.as_str());
+                self.add_message(TimelineItem::from_message_event(
+                    event,
+                    sender_name,
+                    homeserver_url.clone(),
+                ));
+            }
+            AnySyncRoomEvent::MessageLike(AnySyncMessageLikeEvent::RoomMessage(
+                SyncMessageLikeEvent::Redacted(event),
+            )) => {
+                self.add_message(TimelineItem::from_redacted_event(event, &self.members));
+            }
+            // Messages which are still encrypted could not be decrypted
+            AnySyncRoomEvent::MessageLike(AnySyncMessageLikeEvent::RoomEncrypted(
+                SyncMessageLikeEvent::Original(event),
+            )) => {
+                let sender_name = self.members.display_name(event.sender.as_str());
+                self.add_message(TimelineItem::from_encrypted_event(event, sender_name));
+            }
+            AnySyncRoomEvent::MessageLike(AnySyncMessageLikeEvent::RoomEncrypted(
+                SyncMessageLikeEvent::Redacted(event),
+            )) => {
+                self.add_message(TimelineItem::from_redacted_event(event, &self.members));
+            }
+            AnySyncRoomEvent::MessageLike(AnySyncMessageLikeEvent::Reaction(
+                SyncMessageLikeEvent::Original(event),
+            )) => {
+                self.add_reaction(
+                    event.content.relates_to.event_id.as_str(),
+                    &event.content.relates_to.key,
+                    event.sender.as_str(),
+                    event.event_id.as_str(),
+                );
+            }
+            AnySyncRoomEvent::State(event) => self.add_state_event(&event),
+            _ => {}
+        }
     }
 
-    /// Change the selected room to the next one
-    pub fn next_room(&mut self) {
-        if self.rooms.is_empty() {
-            return;
-        }
-        let i = match self.state.selected() {
-            Some(i) => {
-                if i >= self.rooms.len() - 1 {
-                    0
-                } else {
-                    i + 1
+    /// Add a system line for a membership, topic or name change to the timeline.
+    /// Other state events are ignored.
+    /// # Arguments
+    /// * `event` - The state event.
+    pub fn add_state_event(&mut self, event: &AnySyncStateEvent) {
+        let body = match event {
+            AnySyncStateEvent::RoomMember(SyncStateEvent::Original(event)) => {
+                match membership_body(event, &self.members) {
+                    Some(body) => body,
+                    None => return,
                 }
             }
-            None => 0,
+            AnySyncStateEvent::RoomTopic(SyncStateEvent::Original(event)) => {
+                match event.content.topic.as_str() {
+                    "" => format!(
+                        "{} removed the topic",
+                        self.members.display_name(event.sender.as_str())
+                    ),
+                    topic => format!(
+                        "{} changed the topic to {}",
+                        self.members.display_name(event.sender.as_str()),
+                        topic
+                    ),
+                }
+            }
+            AnySyncStateEvent::RoomName(SyncStateEvent::Original(event)) => {
+                match &event.content.name {
+                    Some(name) => format!(
+                        "{} changed the room name to {}",
+                        self.members.display_name(event.sender.as_str()),
+                        name
+                    ),
+                    None => format!(
+                        "{} removed the room name",
+                        self.members.display_name(event.sender.as_str())
+                    ),
+                }
+            }
+            _ => return,
         };
-        self.state.select(Some(i));
+        let sender = event.sender();
+        self.add_message(TimelineItem::from_state_change(
+            event.event_id(),
+            sender,
+            self.members.display_name(sender.as_str()),
+            event.origin_server_ts(),
+            body,
+        ));
     }
 
-    /// Change the selected room to the previous one
-    pub fn previous_room(&mut self) {
-        if self.rooms.is_empty() {
+    /// Returns the main timeline and the timelines of all threads
+    fn timelines_mut(&mut self) -> impl Iterator<Item = &mut ScrollableMessageList> {
+        std::iter::once(&mut self.messages).chain(self.threads.values_mut())
+    }
+
+    /// Add a message to the main timeline or to the timeline of its thread.
+    /// Edits are applied to the edited message in any timeline.
+    /// # Arguments
+    /// * `message` - The message.
+    pub fn add_message(&mut self, message: TimelineItem) {
+        if let Some(event_id) = &message.replaces {
+            for timeline in self.timelines_mut() {
+                timeline.apply_edit(
+                    event_id,
+                    &message.sender,
+                    message.content.clone(),
+                    message.body.clone(),
+                );
+            }
             return;
         }
-        let i = match self.state.selected() {
+        match &message.thread_root {
+            Some(root) => self
+                .threads
+                .entry(root.to_string())
+                .or_insert_with(ScrollableMessageList::new)
+                .add_message(message),
+            None => self.messages.add_message(message),
+        }
+    }
+
+    /// Add a reaction to the message it reacts to in any timeline
+    /// # Arguments
+    /// * `event_id` - The event id of the message
+    /// * `key` - The reaction, usually an emoji
+    /// * `sender` - The user id of the sender of the reaction
+    /// * `reaction_id` - The event id of the reaction
+    pub fn add_reaction(&mut self, event_id: &str, key: &str, sender: &str, reaction_id: &str) {
+        for timeline in self.timelines_mut() {
+            timeline.add_reaction(event_id, key, sender, reaction_id);
+        }
+    }
+
+    /// Replace a deleted message in any timeline with a placeholder or remove a deleted reaction
+    /// # Arguments
+    /// * `event_id` - The event id of the deleted message or reaction
+    /// * `body` - The placeholder text
+    pub fn apply_redaction(&mut self, event_id: &str, body: String) {
+        for timeline in self.timelines_mut() {
+            timeline.apply_redaction(event_id, body.clone());
+        }
+    }
+
+    /// Restore a message in any timeline whose edit could not be sent
+    /// # Arguments
+    /// * `original` - The message before the edit
+    /// * `body` - The body of the edit
+    pub fn revert_edit(&mut self, original: &TimelineItem, body: &str) {
+        for timeline in self.timelines_mut() {
+            timeline.revert_edit(original, body);
+        }
+    }
+
+    /// Update the send state of a message sent by this client in any timeline
+    /// # Arguments
+    /// * `transaction_id` - The transaction id of the message
+    /// * `event_id` - The event id returned by the homeserver, None if sending failed
+    pub fn update_send_state(&mut self, transaction_id: &str, event_id: Option<String>) {
+        for timeline in self.timelines_mut() {
+            timeline.update_send_state(transaction_id, event_id.clone());
+        }
+    }
+
+    /// Returns the messages which could not be decrypted in any timeline
+    pub fn undecryptable_events(&self) -> Vec<OriginalSyncRoomEncryptedEvent> {
+        std::iter::once(&self.messages)
+            .chain(self.threads.values())
+            .flat_map(|timeline| timeline.messages.iter())
+            .filter_map(|m| match &m.content {
+                TimelineContent::UnableToDecrypt(event) => Some(*event.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Replace the placeholder of a message which could be decrypted with new room keys.
+    /// Messages of the main timeline keep their position,
+    /// edits, thread replies and reactions are added like new events.
+    /// # Arguments
+    /// * `event` - The decrypted event.
+    /// * `homeserver_url` - The homeserver url.
+    pub fn replace_decrypted(&mut self, event: AnySyncRoomEvent, homeserver_url: &Url) {
+        let event_id = event.event_id().to_string();
+        if let AnySyncRoomEvent::MessageLike(AnySyncMessageLikeEvent::RoomMessage(
+            SyncMessageLikeEvent::Original(event),
+        )) = &event
+        {
+            let sender_name = self.members.display_name(event.sender.as_str());
+            let message = TimelineItem::from_message_event(
+                event.clone(),
+                sender_name,
+                homeserver_url.clone(),
+            );
+            if message.replaces.is_none()
+                && message.thread_root.is_none()
+                && self.messages.replace_placeholder(&message)
+            {
+                return;
+            }
+        }
+        for timeline in self.timelines_mut() {
+            timeline.remove_placeholder(&event_id);
+        }
+        self.add_event(event, homeserver_url);
+    }
+
+    /// Returns the number of replies in the thread of a message
+    /// # Arguments
+    /// * `event_id` - The event id of the thread root
+    pub fn thread_replies(&self, event_id: &str) -> usize {
+        match self.threads.get(event_id) {
+            Some(thread) => thread.messages.len(),
+            None => 0,
+        }
+    }
+
+    /// Returns the message with the given event id from any timeline
+    /// # Arguments
+    /// * `event_id` - The event id of the message
+    pub fn find_message(&self, event_id: &str) -> Option<&TimelineItem> {
+        std::iter::once(&self.messages)
+            .chain(self.threads.values())
+            .flat_map(|timeline| timeline.messages.iter())
+            .find(|m| m.event_id.as_deref() == Some(event_id))
+    }
+
+    /// Returns who is typing in the room, like "Alice and Bob are typing…"
+    pub fn typing_text(&self) -> Option<String> {
+        let names: Vec<String> = self
+            .typing
+            .iter()
+            .map(|user_id| self.members.display_name(user_id))
+            .collect();
+        match names.as_slice() {
+            [] => None,
+            [name] => Some(format!("{} is typing…", name)),
+            [first, second] => Some(format!("{} and {} are typing…", first, second)),
+            [first, second, third] => {
+                Some(format!("{}, {} and {} are typing…", first, second, third))
+            }
+            [first, second, others @ ..] => Some(format!(
+                "{}, {} and {} others are typing…",
+                first,
+                second,
+                others.len()
+            )),
+        }
+    }
+
+    /// Returns the thread root with the given event id
+    /// # Arguments
+    /// * `event_id` - The event id of the thread root
+    pub fn thread_root(&self, event_id: &str) -> Option<&TimelineItem> {
+        self.messages
+            .messages
+            .iter()
+            .find(|m| m.event_id.as_deref() == Some(event_id))
+    }
+}
+
+/// Scrollable list of rooms
+pub struct ScrollableRoomList {
+    pub state: ListState,
+    pub rooms: Vec<Room>,
+}
+
+impl ScrollableRoomList {
+    /// Create a new room list
+    pub fn new() -> ScrollableRoomList {
+        ScrollableRoomList {
+            state: ListState::default(),
+            rooms: Vec::new(),
+        }
+    }
+
+    /// Add a new room to the list
+    ///
+    /// # Arguments
+    /// * `room` - The room to add
+    /// * `homeserver_url` - The homeserver url
+    pub async fn add_room(&mut self, room: MatrixRoom, homeserver_url: Url) {
+        let room = Room::new(room, homeserver_url).await;
+        self.rooms.push(room);
+    }
+
+    /// Change the selected room to the next one
+    pub fn next_room(&mut self) {
+        if self.rooms.is_empty() {
+            return;
+        }
+        // Messages read while the room was open are not new anymore when it is opened again
+        if let Some(room) = self.get_current_room() {
+            room.read_marker = room.fully_read.clone();
+        }
+        let i = match self.state.selected() {
+            Some(i) => {
+                if i >= self.rooms.len() - 1 {
+                    0
+                } else {
+                    i + 1
+                }
+            }
+            None => 0,
+        };
+        self.state.select(Some(i));
+    }
+
+    /// Change the selected room to the previous one
+    pub fn previous_room(&mut self) {
+        if self.rooms.is_empty() {
+            return;
+        }
+        // Messages read while the room was open are not new anymore when it is opened again
+        if let Some(room) = self.get_current_room() {
+            room.read_marker = room.fully_read.clone();
+        }
+        let i = match self.state.selected() {
             Some(i) => {
                 if i == 0 {
                     self.rooms.len() - 1
@@ -306,120 +1471,1234 @@ impl ScrollableRoomList {
                     i - 1
                 }
             }
-            None => 0,
+            None => 0,
+        };
+        self.state.select(Some(i));
+    }
+
+    /// Returns the selected room
+    pub fn get_current_room(&mut self) -> Option<&mut Room> {
+        let i = match self.state.selected() {
+            Some(i) => i,
+            None => return None,
+        };
+        self.rooms.get_mut(i)
+    }
+}
+
+/// Selectable tabs in the UI
+#[derive(PartialEq, Eq)]
+pub enum Tabs {
+    Room,
+    Members,
+    Messages,
+    Input,
+    /// The messages of the thread pane
+    Thread,
+    /// The input of the thread pane
+    ThreadInput,
+}
+
+/// What the input is used for besides sending a new message
+pub enum InputTarget {
+    /// The input is sent as reply to the message
+    Reply(TimelineItem),
+    /// The input replaces the text of the own message
+    Edit(TimelineItem),
+    /// The input is the optional reason for deleting the message
+    Redact(TimelineItem),
+    /// The input is a reaction to the message
+    React(TimelineItem),
+}
+
+/// A running device verification, shown in a modal
+pub struct VerificationState {
+    /// The user who is verified
+    pub user_id: String,
+    pub request: Option<VerificationRequest>,
+    pub sas: Option<SasVerification>,
+    /// Whether the own user requested the verification
+    pub we_started: bool,
+    /// Whether an incoming request or SAS verification was accepted
+    pub accepted: bool,
+    /// Error shown instead of the verification
+    pub error: Option<String>,
+    sas_started: bool,
+}
+
+impl VerificationState {
+    /// Returns true if the verification can not continue anymore
+    pub fn is_finished(&self) -> bool {
+        if self.error.is_some() {
+            return true;
+        }
+        match (&self.sas, &self.request) {
+            (Some(sas), _) => sas.is_done() || sas.is_cancelled(),
+            (None, Some(request)) => request.is_cancelled(),
+            (None, None) => true,
+        }
+    }
+}
+
+/// The state of the application
+pub struct App {
+    pub rooms: ScrollableRoomList,
+    pub current_tab: Tabs,
+    pub input: String,
+    /// The message the input replies to, edits or deletes
+    pub input_target: Option<InputTarget>,
+    /// The event id of the root of the thread shown in the thread pane
+    pub thread: Option<String>,
+    /// The input of the thread pane
+    pub thread_input: String,
+    pub client: Client,
+    /// The user id of the logged in user
+    pub user_id: String,
+    pub config: Config,
+    pub verification: Option<VerificationState>,
+    /// Users whose identity is verified
+    pub verified_users: HashSet<String>,
+    /// Users whose identity was already checked, verified or not
+    checked_users: HashSet<String>,
+    /// Result of the last command
+    pub status: Option<String>,
+    /// Status updates of background tasks
+    pub tx_status: Sender<String>,
+    pub rx_status: Receiver<String>,
+    /// Room id, transaction id and event id of sent messages
+    pub tx_sent: Sender<(String, String, Option<String>)>,
+    pub rx_sent: Receiver<(String, String, Option<String>)>,
+    /// Room id, the message before the edit and the body of edits which could not be sent
+    pub tx_failed_edits: Sender<(String, TimelineItem, String)>,
+    pub rx_failed_edits: Receiver<(String, TimelineItem, String)>,
+    /// Room id, events, oldest event id and state of loaded older history
+    pub tx_history: Sender<(String, Vec<AnySyncRoomEvent>, Option<String>, HistoryState)>,
+    pub rx_history: Receiver<(String, Vec<AnySyncRoomEvent>, Option<String>, HistoryState)>,
+    /// Room id and the user ids and event ids of the read receipts loaded from the store
+    pub tx_loaded_receipts: Sender<(String, Vec<(String, String)>)>,
+    pub rx_loaded_receipts: Receiver<(String, Vec<(String, String)>)>,
+    /// Room id and events which could be decrypted with newly imported room keys
+    pub tx_decrypted: Sender<(String, Vec<AnySyncRoomEvent>)>,
+    pub rx_decrypted: Receiver<(String, Vec<AnySyncRoomEvent>)>,
+    /// The file the state of the key backup is saved in
+    backup_file: PathBuf,
+    /// The room the own typing notice runs in and when it was sent last
+    typing_notice: Option<(String, Instant)>,
+}
+
+impl App {
+    /// Returns a new App instance with the given homeserver url and username.
+    /// Load rooms from client.
+    /// # Arguments
+    /// * `client` - The client to use
+    /// * `config` - The loaded config
+    /// * `backup_file` - The file the state of the key backup is saved in
+    /// # Returns
+    /// A new App instance.
+    pub async fn new(client: Client, config: Config, backup_file: PathBuf) -> App {
+        let (tx_status, rx_status) = mpsc::channel(100);
+        let (tx_sent, rx_sent) = mpsc::channel(100);
+        let (tx_failed_edits, rx_failed_edits) = mpsc::channel(100);
+        let (tx_history, rx_history) = mpsc::channel(100);
+        let (tx_loaded_receipts, rx_loaded_receipts) = mpsc::channel(100);
+        let (tx_decrypted, rx_decrypted) = mpsc::channel(100);
+        let user_id = match client.user_id().await {
+            Some(user_id) => user_id.to_string(),
+            None => "".to_string(),
+        };
+        let mut app = App {
+            rooms: ScrollableRoomList::new(),
+            current_tab: Tabs::Room,
+            input: String::new(),
+            input_target: None,
+            thread: None,
+            thread_input: String::new(),
+            client,
+            user_id,
+            config,
+            verification: None,
+            verified_users: HashSet::new(),
+            checked_users: HashSet::new(),
+            status: None,
+            tx_status,
+            rx_status,
+            tx_sent,
+            rx_sent,
+            tx_failed_edits,
+            rx_failed_edits,
+            tx_history,
+            rx_history,
+            tx_loaded_receipts,
+            rx_loaded_receipts,
+            tx_decrypted,
+            rx_decrypted,
+            backup_file,
+            typing_notice: None,
+        };
+        app.load_rooms().await;
+        app.check_new_members().await;
+        app
+    }
+
+    /// Checks the verified state of the members which were not checked yet
+    pub async fn check_new_members(&mut self) {
+        let mut user_ids = HashSet::new();
+        for room in &self.rooms.rooms {
+            for (_, user_id) in &room.members.members {
+                if !self.checked_users.contains(user_id) {
+                    user_ids.insert(user_id.to_string());
+                }
+            }
+        }
+        self.update_verified_users(user_ids.into_iter().collect())
+            .await;
+    }
+
+    /// Update the verified state of users
+    /// # Arguments
+    /// * `user_ids` - The users to check
+    async fn update_verified_users(&mut self, user_ids: Vec<String>) {
+        for user_id in user_ids {
+            if self.client.is_user_verified(&user_id).await {
+                self.verified_users.insert(user_id.clone());
+            } else {
+                self.verified_users.remove(&user_id);
+            }
+            self.checked_users.insert(user_id);
+        }
+    }
+
+    /// Request a verification with a user and show it in the verification modal
+    /// # Arguments
+    /// * `user_id` - The user to verify
+    pub async fn start_verification(&mut self, user_id: String) {
+        let request = self.client.request_verification(&user_id).await;
+        let error = match request {
+            Some(_) => None,
+            None => Some(format!(
+                "Could not verify {}, the user has not set up cross-signing",
+                user_id
+            )),
+        };
+        self.verification = Some(VerificationState {
+            user_id,
+            request,
+            sas: None,
+            we_started: true,
+            accepted: true,
+            error,
+            sas_started: false,
+        });
+    }
+
+    /// Handles verification events.
+    /// Incoming requests open the verification modal,
+    /// started SAS verifications are attached to the running verification.
+    /// # Arguments
+    /// * `event` - The event to handle.
+    pub fn handle_verification_event(&mut self, event: VerificationEvent) {
+        match event {
+            VerificationEvent::Request(request) => {
+                // Don't replace a running verification
+                if let Some(verification) = &self.verification {
+                    if !verification.is_finished() {
+                        return;
+                    }
+                }
+                self.verification = Some(VerificationState {
+                    user_id: request.other_user_id().to_string(),
+                    request: Some(request),
+                    sas: None,
+                    we_started: false,
+                    accepted: false,
+                    error: None,
+                    sas_started: false,
+                });
+            }
+            VerificationEvent::Sas(sas) => {
+                let user_id = sas.other_device().user_id().to_string();
+                match &mut self.verification {
+                    Some(verification)
+                        if verification.user_id == user_id && !verification.is_finished() =>
+                    {
+                        // The SAS was started by the other side, the user has to accept it first
+                        verification.sas = Some(sas);
+                        verification.accepted = false;
+                    }
+                    _ => {
+                        self.verification = Some(VerificationState {
+                            user_id,
+                            request: None,
+                            sas: Some(sas),
+                            we_started: false,
+                            accepted: false,
+                            error: None,
+                            sas_started: true,
+                        });
+                    }
+                }
+            }
+        }
+    }
+
+    /// Starts the SAS verification once a request of the own user was accepted
+    pub async fn update_verification(&mut self) {
+        let verification = match &mut self.verification {
+            Some(verification) => verification,
+            None => return,
+        };
+        if !verification.we_started || verification.sas_started || verification.sas.is_some() {
+            return;
+        }
+        if let Some(request) = &verification.request {
+            if request.is_ready() {
+                verification.sas_started = true;
+                if let Ok(Some(sas)) = request.start_sas().await {
+                    verification.sas = Some(sas);
+                }
+            }
+        }
+    }
+
+    /// Confirms the current step of the verification.
+    /// Accepts an incoming request or SAS verification, confirms matching emojis
+    /// or closes a finished verification.
+    pub async fn confirm_verification(&mut self) {
+        let verification = match &mut self.verification {
+            Some(verification) => verification,
+            None => return,
+        };
+        if verification.is_finished() {
+            self.close_verification().await;
+            return;
+        }
+        match (&verification.sas, &verification.request) {
+            (Some(sas), _) => {
+                if !verification.accepted {
+                    verification.accepted = true;
+                    if let Err(err) = sas.accept().await {
+                        self.status = Some(format!("Could not accept the verification: {}", err));
+                    }
+                // The emojis are only shown after the keys were exchanged
+                } else if sas.emoji().is_some() || sas.decimals().is_some() {
+                    if let Err(err) = sas.confirm().await {
+                        self.status = Some(format!("Could not confirm the verification: {}", err));
+                    }
+                }
+            }
+            (None, Some(request)) => {
+                if !verification.accepted {
+                    verification.accepted = true;
+                    if let Err(err) = request.accept().await {
+                        self.status = Some(format!("Could not accept the verification: {}", err));
+                    }
+                }
+            }
+            (None, None) => {}
+        }
+    }
+
+    /// Cancels the verification or closes a finished verification.
+    /// Emojis which are shown are reported as not matching.
+    pub async fn cancel_verification(&mut self) {
+        let verification = match &self.verification {
+            Some(verification) => verification,
+            None => return,
+        };
+        if !verification.is_finished() {
+            match (&verification.sas, &verification.request) {
+                (Some(sas), _) => {
+                    let result = if sas.emoji().is_some() || sas.decimals().is_some() {
+                        sas.mismatch().await
+                    } else {
+                        sas.cancel().await
+                    };
+                    if result.is_ok() {};
+                }
+                (None, Some(request)) => {
+                    if (request.cancel().await).is_ok() {};
+                }
+                (None, None) => {}
+            }
+        }
+        self.close_verification().await;
+    }
+
+    /// Closes the verification modal and updates the verified state of the verified user.
+    /// Verifying the own user can change the state of all users, as they are verified
+    /// through the own identity.
+    async fn close_verification(&mut self) {
+        let user_id = match self.verification.take() {
+            Some(verification) => verification.user_id,
+            None => return,
+        };
+        if user_id == self.user_id {
+            let user_ids = self.checked_users.iter().cloned().collect();
+            self.update_verified_users(user_ids).await;
+        } else {
+            self.update_verified_users(vec![user_id]).await;
+        }
+    }
+
+    /// Load the rooms from the homeserver and add them to the room list.
+    async fn load_rooms(&mut self) {
+        let rooms = self.client.rooms();
+
+        for room in rooms {
+            if room.room_type() == RoomType::Joined {
+                self.rooms
+                    .add_room(room, self.client.homeserver().await)
+                    .await;
+            }
+        }
+
+        // Accepts all invites
+        if self.config.invites.policy == InvitePolicy::Accept {
+            let invites = self.client.invited_rooms();
+            for room in invites {
+                room.accept_invitation_background();
+            }
+        }
+    }
+
+    /// Handles OriginalSyncRoomMessage events.
+    /// Takes data from the event and adds it to room.
+    /// Throws system notifications if the event is a message.
+    /// # Arguments
+    /// * `event` - The event to handle.
+    /// * `room` - The room to handle the event in.
+    /// * `client` - The client used to receive messages.
+    pub async fn handle_matrix_message_event(
+        &mut self,
+        event: OriginalSyncRoomMessageEvent,
+        room: MatrixRoom,
+        client: Client,
+    ) {
+        let room = room.room_id().to_string();
+
+        match self.rooms.rooms.iter_mut().find(|r| r.id == room) {
+            Some(r) => {
+                let sender_name = r.members.display_name(event.sender.as_str());
+                let message =
+                    TimelineItem::from_message_event(event, sender_name, client.homeserver().await);
+                let notifications = &self.config.notifications;
+                if notifications.enabled
+                    && message.sender != self.user_id
+                    && message.replaces.is_none()
+                    && notify_rust::Notification::new()
+                        .summary(&message.sender_name)
+                        .body(if notifications.show_body {
+                            &message.body
+                        } else {
+                            "New message"
+                        })
+                        .icon("matrix")
+                        .show()
+                        .is_ok()
+                {}
+                r.add_message(message);
+            }
+            None => {}
+        }
+    }
+
+    /// Handles OriginalSyncRoomEncryptedEvent events.
+    /// These are messages which could not be decrypted,
+    /// a placeholder is added to the room instead of the message.
+    /// # Arguments
+    /// * `event` - The event to handle.
+    /// * `room` - The room to handle the event in.
+    pub fn handle_matrix_encrypted_event(
+        &mut self,
+        event: OriginalSyncRoomEncryptedEvent,
+        room: MatrixRoom,
+    ) {
+        let room = room.room_id().to_string();
+
+        if let Some(r) = self.rooms.rooms.iter_mut().find(|r| r.id == room) {
+            let sender_name = r.members.display_name(event.sender.as_str());
+            r.add_message(TimelineItem::from_encrypted_event(event, sender_name));
+        }
+    }
+
+    /// Starts, renews or stops the own typing notice after the input changed.
+    /// The notice runs in the current room while the input or the thread input holds a message,
+    /// commands starting with '/' are not announced.
+    pub fn update_typing_notice(&mut self) {
+        let room_id = self.rooms.get_current_room().map(|room| room.id.clone());
+        let typing = [&self.input, &self.thread_input]
+            .iter()
+            .any(|input| !input.is_empty() && !input.starts_with('/'));
+
+        if let Some((typing_room, _)) = self.typing_notice.clone() {
+            if !typing || room_id.as_ref() != Some(&typing_room) {
+                self.send_typing_notice(typing_room, false);
+                self.typing_notice = None;
+            }
+        }
+        if let (true, Some(room_id)) = (typing, room_id) {
+            let due = match &self.typing_notice {
+                Some((_, sent)) => sent.elapsed() >= TYPING_NOTICE_RESEND,
+                None => true,
+            };
+            if due {
+                self.send_typing_notice(room_id.clone(), true);
+                self.typing_notice = Some((room_id, Instant::now()));
+            }
+        }
+    }
+
+    /// Sends a typing notice in the background
+    /// # Arguments
+    /// * `room_id` - The room the user is typing in
+    /// * `typing` - Whether the user is typing
+    fn send_typing_notice(&self, room_id: String, typing: bool) {
+        let client = self.client.clone();
+        tokio::spawn(async move {
+            if (client.typing_notice(&room_id, typing).await).is_ok() {};
+        });
+    }
+
+    /// Handles the input when the send key is pressed.
+    /// Depending on the input target the input is sent as new message, reply, edit
+    /// or reaction, or used as reason of a deletion. Input starting with '/' is run as command.
+    pub async fn submit_input(&mut self) {
+        let input: String = self.input.drain(..).collect();
+        match self.input_target.take() {
+            Some(InputTarget::Edit(original)) => self.send_edit(original, &input),
+            Some(InputTarget::Redact(message)) => self.redact_message(message, &input),
+            Some(InputTarget::React(message)) => self.toggle_reaction(message, &input),
+            target => {
+                if input.starts_with('/')
+                    && !input.starts_with(PLAIN_PREFIX)
+                    && !input.starts_with(EMOTE_PREFIX)
+                {
+                    // Commands don't end a reply
+                    self.input_target = target;
+                    self.handle_command(&input).await;
+                } else {
+                    let reply_to = match target {
+                        Some(InputTarget::Reply(message)) => Some(message),
+                        _ => None,
+                    };
+                    self.send_message(&input, reply_to, None);
+                }
+            }
+        }
+    }
+
+    /// Sends the input of the thread pane to the open thread
+    pub fn submit_thread_input(&mut self) {
+        let input: String = self.thread_input.drain(..).collect();
+        if let Some(root) = self.thread.clone() {
+            self.send_message(&input, None, Some(root));
+        }
+    }
+
+    /// Returns the message without the plain text prefix and whether it is sent as markdown
+    /// # Arguments
+    /// * `input` - The input
+    fn markdown_message<'a>(&self, input: &'a str) -> (&'a str, bool) {
+        match input.strip_prefix(PLAIN_PREFIX) {
+            Some(message) => (message, false),
+            None => (input, self.config.compose.markdown),
+        }
+    }
+
+    /// Sends a text message to the current room in the background.
+    /// The message is formatted as markdown if enabled and not prefixed with "/plain".
+    /// Messages prefixed with "/me" are sent as emote.
+    /// The message is shown as sending until the homeserver acknowledged it.
+    /// # Arguments
+    /// * `message` - The message to send
+    /// * `reply_to` - The message the message replies to
+    /// * `thread_root` - The event id of the root of the thread the message is sent to
+    fn send_message(
+        &mut self,
+        message: &str,
+        reply_to: Option<TimelineItem>,
+        thread_root: Option<String>,
+    ) {
+        let (message, emote) = match message.strip_prefix(EMOTE_PREFIX) {
+            Some(action) => (action, true),
+            None => (message, false),
+        };
+        let (message, markdown) = self.markdown_message(message);
+        if message.is_empty() {
+            return;
+        }
+        let user_id = self.user_id.clone();
+        let room = match self.rooms.get_current_room() {
+            Some(room) => room,
+            None => return,
+        };
+
+        let content = match emote {
+            true => emote_content(message, markdown),
+            false => text_content(message, markdown),
+        };
+        let in_reply_to = reply_to.as_ref().and_then(|m| m.event_id.clone());
+        let content = match (&reply_to, in_reply_to.as_deref().map(EventId::parse)) {
+            (Some(original), Some(Ok(event_id))) => reply_content(
+                content,
+                &room.id,
+                &event_id,
+                &original.sender,
+                &original.body,
+            ),
+            _ => match thread_root.as_deref().map(EventId::parse) {
+                Some(Ok(root)) => {
+                    // Clients without thread support show the message as reply to the latest one
+                    let latest = room
+                        .threads
+                        .get(root.as_str())
+                        .and_then(|t| t.messages.iter().rev().find_map(|m| m.event_id.clone()))
+                        .and_then(|id| EventId::parse(id).ok())
+                        .unwrap_or_else(|| root.clone());
+                    thread_content(content, &root, &latest)
+                }
+                _ => content,
+            },
+        };
+        let transaction_id = TransactionId::new();
+        room.add_message(TimelineItem {
+            event_id: None,
+            transaction_id: Some(transaction_id.to_string()),
+            sender_name: room.members.display_name(&user_id),
+            sender: user_id,
+            timestamp: Utc::now(),
+            content: TimelineContent::Message(content.clone()),
+            body: message.to_string(),
+            in_reply_to,
+            replaces: None,
+            thread_root,
+            edited: false,
+            reactions: Vec::new(),
+            send_state: SendState::Sending,
+            rendered: None,
+        });
+
+        let client = self.client.clone();
+        let room_id = room.id.clone();
+        let tx = self.tx_sent.clone();
+        tokio::spawn(async move {
+            let event_id = client
+                .send_message(&room_id, content, &transaction_id)
+                .await;
+            if (tx
+                .send((room_id, transaction_id.to_string(), event_id))
+                .await)
+                .is_ok()
+            {};
+        });
+    }
+
+    /// Sends an edit of an own message in the background.
+    /// The edit is shown immediately and reverted if it can not be sent.
+    /// # Arguments
+    /// * `original` - The edited message
+    /// * `message` - The new text of the message
+    fn send_edit(&mut self, original: TimelineItem, message: &str) {
+        let (message, markdown) = self.markdown_message(message);
+        if message.is_empty() {
+            return;
+        }
+        let event_id = match original.event_id.as_deref().map(EventId::parse) {
+            Some(Ok(event_id)) => event_id,
+            _ => return,
+        };
+        let room = match self.rooms.get_current_room() {
+            Some(room) => room,
+            None => return,
+        };
+
+        for timeline in room.timelines_mut() {
+            timeline.apply_edit(
+                event_id.as_str(),
+                &original.sender,
+                TimelineContent::Message(text_content(message, markdown)),
+                message.to_string(),
+            );
+        }
+
+        let content = edit_content(message, markdown, &event_id);
+        let client = self.client.clone();
+        let room_id = room.id.clone();
+        let body = message.to_string();
+        let tx = self.tx_failed_edits.clone();
+        tokio::spawn(async move {
+            let transaction_id = TransactionId::new();
+            let event_id = client
+                .send_message(&room_id, content, &transaction_id)
+                .await;
+            if event_id.is_none() && (tx.send((room_id, original, body)).await).is_ok() {};
+        });
+    }
+
+    /// Deletes a message in the background.
+    /// The message is replaced once the redaction is received from the homeserver.
+    /// # Arguments
+    /// * `message` - The message to delete
+    /// * `reason` - The reason for the deletion, may be empty
+    fn redact_message(&mut self, message: TimelineItem, reason: &str) {
+        let event_id = match message.event_id {
+            Some(event_id) => event_id,
+            None => return,
+        };
+        let room_id = match self.rooms.get_current_room() {
+            Some(room) => room.id.clone(),
+            None => return,
+        };
+        let reason = match reason.trim() {
+            "" => None,
+            reason => Some(reason.to_string()),
+        };
+
+        let client = self.client.clone();
+        let tx = self.tx_status.clone();
+        self.status = Some("Deleting message...".to_string());
+        tokio::spawn(async move {
+            let status = match client
+                .redact_event(&room_id, &event_id, reason.as_deref())
+                .await
+            {
+                Ok(_) => "Message deleted".to_string(),
+                Err(err) => format!("Could not delete message: {}", err),
+            };
+            if (tx.send(status).await).is_ok() {};
+        });
+    }
+
+    /// Reacts to a message in the background.
+    /// If the own user already reacted with the same key, the reaction is removed instead.
+    /// # Arguments
+    /// * `message` - The message to react to
+    /// * `input` - The emoji or its shortcode
+    fn toggle_reaction(&mut self, message: TimelineItem, input: &str) {
+        let key = match reaction_key(input) {
+            Some(key) => key,
+            None => {
+                self.status = Some(format!("Unknown reaction {}", input.trim()));
+                return;
+            }
+        };
+        let event_id = match message.event_id {
+            Some(event_id) => event_id,
+            None => return,
+        };
+        let room = match self.rooms.get_current_room() {
+            Some(room) => room,
+            None => return,
+        };
+        // The reactions may have changed since the message was selected,
+        // the message may also be a reply in a thread
+        let own_reaction = room
+            .find_message(&event_id)
+            .and_then(|m| m.reactions.iter().find(|r| r.key == key))
+            .and_then(|r| r.event_id_of(&self.user_id))
+            .map(|id| id.to_string());
+
+        let client = self.client.clone();
+        let room_id = room.id.clone();
+        let tx = self.tx_status.clone();
+        tokio::spawn(async move {
+            let result = match own_reaction {
+                Some(reaction_id) => client.redact_event(&room_id, &reaction_id, None).await,
+                None => client.send_reaction(&room_id, &event_id, &key).await,
+            };
+            if let Err(err) = result {
+                if (tx
+                    .send(format!("Could not react with {}: {}", key, err))
+                    .await)
+                    .is_ok()
+                {};
+            }
+        });
+    }
+
+    /// Opens the thread of the selected message of the current room in the thread pane.
+    /// The selected message can be the thread root or a message in the thread.
+    pub fn open_thread(&mut self) {
+        let message = match self.selected_message() {
+            Some(message) => message,
+            None => return,
+        };
+        let root = match message.thread_root.or(message.event_id) {
+            Some(root) => root,
+            None => {
+                self.status = Some("The message has not been sent yet".to_string());
+                return;
+            }
+        };
+        if let Some(room) = self.rooms.get_current_room() {
+            room.threads
+                .entry(root.to_string())
+                .or_insert_with(ScrollableMessageList::new);
+        }
+        self.thread = Some(root);
+        self.thread_input.clear();
+        self.current_tab = Tabs::Thread;
+    }
+
+    /// Selects the previous message of the current room.
+    /// Older history is loaded in the background when the oldest loaded message is selected.
+    pub fn previous_message(&mut self) {
+        let room = match self.rooms.get_current_room() {
+            Some(room) => room,
+            None => return,
+        };
+        let at_top = matches!(room.messages.state.selected(), None | Some(0));
+        room.messages.previous_message();
+        if !at_top || room.history != HistoryState::Idle {
+            return;
+        }
+        room.history = HistoryState::Loading;
+
+        let before = room.oldest_event.clone();
+        if let Some(requests) = &room.history_requests {
+            if requests.try_send(before.clone()).is_ok() {
+                return;
+            }
+        }
+
+        // The task of the room ended or was not started yet
+        let matrix_room = match RoomId::parse(&room.id)
+            .ok()
+            .and_then(|id| self.client.get_room(&id))
+        {
+            Some(matrix_room) => matrix_room,
+            None => {
+                room.history = HistoryState::Idle;
+                return;
+            }
+        };
+        let (requests, rx_requests) = mpsc::channel(1);
+        if requests.try_send(before).is_ok() {};
+        room.history_requests = Some(requests);
+        tokio::spawn(serve_history(
+            matrix_room,
+            rx_requests,
+            self.tx_history.clone(),
+        ));
+    }
+
+    /// Closes the thread pane
+    pub fn close_thread(&mut self) {
+        self.thread = None;
+        self.thread_input.clear();
+        if self.current_tab == Tabs::Thread || self.current_tab == Tabs::ThreadInput {
+            self.current_tab = Tabs::Messages;
+        }
+        self.update_typing_notice();
+    }
+
+    /// Returns the timeline of the open thread of the current room
+    pub fn current_thread(&mut self) -> Option<&mut ScrollableMessageList> {
+        let root = self.thread.as_ref()?;
+        match self.rooms.get_current_room() {
+            Some(room) => room.threads.get_mut(root),
+            None => None,
+        }
+    }
+
+    /// Returns the selected message of the current room
+    fn selected_message(&mut self) -> Option<TimelineItem> {
+        match self.rooms.get_current_room() {
+            Some(room) => room
+                .messages
+                .state
+                .selected()
+                .and_then(|i| room.messages.messages.get(i).cloned()),
+            None => None,
+        }
+    }
+
+    /// Sets the target of the input and switches to the input.
+    /// Text of a previous edit is removed from the input.
+    /// # Arguments
+    /// * `target` - The new input target
+    fn set_input_target(&mut self, target: InputTarget) {
+        self.cancel_input_target();
+        self.input_target = Some(target);
+        self.current_tab = Tabs::Input;
+    }
+
+    /// Stops replying to, editing, deleting or reacting to a message
+    pub fn cancel_input_target(&mut self) {
+        match self.input_target.take() {
+            Some(InputTarget::Edit(_))
+            | Some(InputTarget::Redact(_))
+            | Some(InputTarget::React(_)) => self.input.clear(),
+            Some(InputTarget::Reply(_)) | None => {}
+        }
+    }
+
+    /// Selects the selected message of the current room as target of a reply
+    pub fn start_reply(&mut self) {
+        match self.selected_message() {
+            Some(message) if matches!(message.content, TimelineContent::State) => {
+                self.status = Some("Room changes can not be replied to".to_string());
+            }
+            Some(message) if message.event_id.is_some() => {
+                self.set_input_target(InputTarget::Reply(message));
+            }
+            Some(_) => {
+                self.status = Some("The message has not been sent yet".to_string());
+            }
+            None => {}
+        }
+    }
+
+    /// Loads the selected own message of the current room into the input for editing
+    pub fn start_edit(&mut self) {
+        let message = match self.selected_message() {
+            Some(message) => message,
+            None => return,
+        };
+        let is_text = match &message.content {
+            TimelineContent::Message(content) => {
+                matches!(content.msgtype, MessageType::Text(_))
+            }
+            TimelineContent::UnableToDecrypt(_)
+            | TimelineContent::Redacted
+            | TimelineContent::State => false,
+        };
+        if message.sender != self.user_id || !is_text {
+            self.status = Some("Only own text messages can be edited".to_string());
+            return;
+        }
+        if message.event_id.is_none() {
+            self.status = Some("The message has not been sent yet".to_string());
+            return;
+        }
+        let body = message.body.clone();
+        self.set_input_target(InputTarget::Edit(message));
+        self.input = body;
+    }
+
+    /// Asks for a reaction to the selected message of the current room
+    pub fn start_reaction(&mut self) {
+        match self.selected_message() {
+            Some(message) if matches!(message.content, TimelineContent::Redacted) => {
+                self.status = Some("The message is deleted".to_string());
+            }
+            Some(message) if matches!(message.content, TimelineContent::State) => {
+                self.status = Some("Room changes can not be reacted to".to_string());
+            }
+            Some(message) if message.event_id.is_some() => {
+                self.set_input_target(InputTarget::React(message));
+                self.input.clear();
+            }
+            Some(_) => {
+                self.status = Some("The message has not been sent yet".to_string());
+            }
+            None => {}
+        }
+    }
+
+    /// Asks for a reason to delete the selected message of the current room
+    pub fn start_redaction(&mut self) {
+        match self.selected_message() {
+            Some(message) if matches!(message.content, TimelineContent::Redacted) => {
+                self.status = Some("The message is already deleted".to_string());
+            }
+            Some(message) if message.event_id.is_some() => {
+                self.set_input_target(InputTarget::Redact(message));
+                self.input.clear();
+            }
+            Some(_) => {
+                self.status = Some("The message has not been sent yet".to_string());
+            }
+            None => {}
+        }
+    }
+
+    /// Updates the send state of a message once the homeserver answered
+    /// # Arguments
+    /// * `room_id` - The room the message was sent to
+    /// * `transaction_id` - The transaction id of the message
+    /// * `event_id` - The event id of the message, None if sending failed
+    pub fn handle_sent_message(
+        &mut self,
+        room_id: String,
+        transaction_id: String,
+        event_id: Option<String>,
+    ) {
+        if event_id.is_none() {
+            self.status = Some("Could not send message".to_string());
+        }
+        if let Some(r) = self.rooms.rooms.iter_mut().find(|r| r.id == room_id) {
+            r.update_send_state(&transaction_id, event_id);
+        }
+    }
+
+    /// Handles an edit which could not be sent, the edited message is restored.
+    /// # Arguments
+    /// * `room_id` - The id of the room of the message.
+    /// * `original` - The message before the edit.
+    /// * `body` - The body of the edit.
+    pub fn handle_failed_edit(&mut self, room_id: String, original: TimelineItem, body: String) {
+        self.status = Some("Could not edit message".to_string());
+        if let Some(r) = self.rooms.rooms.iter_mut().find(|r| r.id == room_id) {
+            r.revert_edit(&original, &body);
+        }
+    }
+
+    /// Handles older history loaded in the background.
+    /// # Arguments
+    /// * `room_id` - The id of the room the history belongs to.
+    /// * `events` - The events from newest to oldest.
+    /// * `oldest_event` - The event id of the oldest read event.
+    /// * `history` - Whether more events can be loaded.
+    pub async fn handle_history(
+        &mut self,
+        room_id: String,
+        events: Vec<AnySyncRoomEvent>,
+        oldest_event: Option<String>,
+        history: HistoryState,
+    ) {
+        let homeserver_url = self.client.homeserver().await;
+        if let Some(r) = self.rooms.rooms.iter_mut().find(|r| r.id == room_id) {
+            if history == HistoryState::Failed {
+                self.status = Some(format!(
+                    "Could not load older messages of {}, the oldest loaded message was not found",
+                    r.name
+                ));
+            }
+            r.add_history(events, oldest_event, history, &homeserver_url);
+        }
+    }
+
+    /// Loads the read receipts of the members of the current room from the store
+    /// in the background, once per room. Newer receipts arrive as receipt events.
+    pub fn load_receipts(&mut self) {
+        let room = match self.rooms.get_current_room() {
+            Some(room) => room,
+            None => return,
         };
-        self.state.select(Some(i));
+        if room.receipts_loaded {
+            return;
+        }
+        room.receipts_loaded = true;
+        let matrix_room = match RoomId::parse(&room.id)
+            .ok()
+            .and_then(|id| self.client.get_room(&id))
+        {
+            Some(matrix_room) => matrix_room,
+            None => return,
+        };
+        let room_id = room.id.clone();
+        let user_ids: Vec<String> = room.members.members.iter().map(|m| m.1.clone()).collect();
+        let tx = self.tx_loaded_receipts.clone();
+        tokio::spawn(async move {
+            let mut receipts = Vec::new();
+            for user_id in user_ids {
+                let user_id = match <&UserId>::try_from(user_id.as_str()) {
+                    Ok(user_id) => user_id,
+                    Err(_) => continue,
+                };
+                if let Ok(Some((event_id, _))) = matrix_room.user_read_receipt(user_id).await {
+                    receipts.push((user_id.to_string(), event_id.to_string()));
+                }
+            }
+            if (tx.send((room_id, receipts)).await).is_ok() {};
+        });
     }
 
-    /// Returns the selected room
-    pub fn get_current_room(&mut self) -> Option<&mut Room> {
-        let i = match self.state.selected() {
-            Some(i) => i,
-            None => return None,
+    /// Handles read receipts loaded from the store.
+    /// Receipts received while they were loaded are newer and kept.
+    /// # Arguments
+    /// * `room_id` - The id of the room the receipts belong to.
+    /// * `receipts` - The user ids and the event ids of their last read events.
+    pub fn handle_loaded_receipts(&mut self, room_id: String, receipts: Vec<(String, String)>) {
+        if let Some(r) = self.rooms.rooms.iter_mut().find(|r| r.id == room_id) {
+            for (user_id, event_id) in receipts {
+                r.receipts.entry(user_id).or_insert(event_id);
+            }
+        }
+    }
+
+    /// Handles messages which could be decrypted with newly imported room keys.
+    /// # Arguments
+    /// * `room_id` - The id of the room the messages belong to.
+    /// * `events` - The decrypted events.
+    pub async fn handle_decrypted(&mut self, room_id: String, events: Vec<AnySyncRoomEvent>) {
+        let homeserver_url = self.client.homeserver().await;
+        if let Some(r) = self.rooms.rooms.iter_mut().find(|r| r.id == room_id) {
+            for event in events {
+                r.replace_decrypted(event, &homeserver_url);
+            }
+        }
+    }
+
+    /// Returns the messages of all rooms which could not be decrypted, by room id
+    fn undecryptable_events(&self) -> Vec<(String, Vec<OriginalSyncRoomEncryptedEvent>)> {
+        self.rooms
+            .rooms
+            .iter()
+            .map(|r| (r.id.clone(), r.undecryptable_events()))
+            .filter(|(_, events)| !events.is_empty())
+            .collect()
+    }
+
+    /// Moves the read marker and the read receipt to the newest message of the current room
+    /// while it follows the newest message.
+    pub fn mark_current_room_read(&mut self) {
+        let room = match self.rooms.get_current_room() {
+            Some(room) => room,
+            None => return,
         };
-        self.rooms.get_mut(i)
+        if !room.messages.is_following() {
+            return;
+        }
+        let event_id = match room
+            .messages
+            .messages
+            .iter()
+            .rev()
+            .find_map(|m| m.event_id.clone())
+        {
+            Some(event_id) => event_id,
+            None => return,
+        };
+        if room.fully_read.as_ref() == Some(&event_id) {
+            return;
+        }
+        room.fully_read = Some(event_id.clone());
+        room.receipts.insert(self.user_id.clone(), event_id.clone());
+
+        let client = self.client.clone();
+        let room_id = room.id.clone();
+        tokio::spawn(async move {
+            if (client.read_marker(&room_id, &event_id).await).is_ok() {};
+        });
     }
-}
 
-/// Selectable tabs in the UI
-#[derive(PartialEq, Eq)]
-pub enum Tabs {
-    Room,
-    Members,
-    Messages,
-    Input,
-}
+    /// Handles the unread counts of a room sent by the homeserver with each sync
+    /// # Arguments
+    /// * `room_id` - The id of the room.
+    /// * `notifications` - The number of unread messages which notify.
+    /// * `highlights` - The number of unread messages which mention the user.
+    pub fn handle_unread_counts(&mut self, room_id: String, notifications: u64, highlights: u64) {
+        if let Some(r) = self.rooms.rooms.iter_mut().find(|r| r.id == room_id) {
+            r.notifications = notifications;
+            r.highlights = highlights;
+        }
+    }
 
-/// The state of the application
-pub struct App {
-    pub rooms: ScrollableRoomList,
-    pub current_tab: Tabs,
-    pub input: String,
-    pub client: Client,
-}
+    /// Returns the number of unread messages which notify in all rooms
+    pub fn total_notifications(&self) -> u64 {
+        self.rooms.rooms.iter().map(|room| room.notifications).sum()
+    }
 
-impl App {
-    /// Returns a new App instance with the given homeserver url and username.
-    /// Load rooms from client.
+    /// Handles SyncReceiptEvent events.
+    /// Moves the read receipts of the users to the read events.
     /// # Arguments
-    /// * `client` - The client to use
-    /// # Returns
-    /// A new App instance.
-    pub async fn new(client: Client) -> App {
-        let mut app = App {
-            rooms: ScrollableRoomList::new(),
-            current_tab: Tabs::Room,
-            input: String::new(),
-            client,
-        };
-        app.load_rooms().await;
-        app
+    /// * `event` - The event to handle.
+    /// * `room` - The room to handle the event in.
+    pub fn handle_matrix_receipt_event(&mut self, event: SyncReceiptEvent, room: MatrixRoom) {
+        let room = room.room_id().to_string();
+
+        if let Some(r) = self.rooms.rooms.iter_mut().find(|r| r.id == room) {
+            for (event_id, receipts) in event.content.iter() {
+                if let Some(users) = receipts.get(&ReceiptType::Read) {
+                    for user_id in users.keys() {
+                        r.receipts.insert(user_id.to_string(), event_id.to_string());
+                    }
+                }
+            }
+        }
     }
 
-    /// Load the rooms from the homeserver and add them to the room list.
-    async fn load_rooms(&mut self) {
-        let rooms = self.client.rooms();
+    /// Handles FullyReadEvent events.
+    /// The read marker can be moved by other devices of the user.
+    /// # Arguments
+    /// * `event` - The event to handle.
+    /// * `room` - The room to handle the event in.
+    pub fn handle_matrix_read_marker_event(&mut self, event: FullyReadEvent, room: MatrixRoom) {
+        let room = room.room_id().to_string();
 
-        for room in rooms {
-            if room.room_type() == RoomType::Joined {
-                self.rooms
-                    .add_room(room, self.client.homeserver().await)
-                    .await;
+        if let Some(r) = self.rooms.rooms.iter_mut().find(|r| r.id == room) {
+            r.fully_read = Some(event.content.event_id.to_string());
+        }
+    }
+
+    /// Handles topic and name changes of a room
+    /// # Arguments
+    /// * `event` - The event to handle.
+    /// * `room` - The room to handle the event in.
+    pub fn handle_matrix_state_event(&mut self, event: AnySyncStateEvent, room: MatrixRoom) {
+        let room = room.room_id().to_string();
+
+        if let Some(r) = self.rooms.rooms.iter_mut().find(|r| r.id == room) {
+            if let AnySyncStateEvent::RoomName(SyncStateEvent::Original(event)) = &event {
+                if let Some(name) = &event.content.name {
+                    r.name = name.to_string();
+                }
             }
+            r.add_state_event(&event);
         }
+    }
 
-        // Accepts all invites
-        let invites = self.client.invited_rooms();
-        for room in invites {
-            room.accept_invitation_background();
+    /// Handles SyncTypingEvent events.
+    /// Replaces the typing members of the room, the own user is left out.
+    /// # Arguments
+    /// * `event` - The event to handle.
+    /// * `room` - The room to handle the event in.
+    pub fn handle_matrix_typing_event(&mut self, event: SyncTypingEvent, room: MatrixRoom) {
+        let room = room.room_id().to_string();
+
+        if let Some(r) = self.rooms.rooms.iter_mut().find(|r| r.id == room) {
+            r.typing = event
+                .content
+                .user_ids
+                .iter()
+                .map(|user_id| user_id.to_string())
+                .filter(|user_id| *user_id != self.user_id)
+                .collect();
         }
     }
 
-    /// Handles OriginalSyncRoomMessage events.
-    /// Takes data from the event and adds it to room.
-    /// Throws system notifications if the event is a message.
+    /// Handles OriginalSyncRoomRedactionEvent events.
+    /// The deleted message is replaced with a placeholder naming who deleted it.
     /// # Arguments
     /// * `event` - The event to handle.
     /// * `room` - The room to handle the event in.
-    /// * `client` - The client used to receive messages.
-    pub async fn handle_matrix_message_event(
+    pub fn handle_matrix_redaction_event(
         &mut self,
-        event: OriginalSyncRoomMessageEvent,
+        event: OriginalSyncRoomRedactionEvent,
         room: MatrixRoom,
-        client: Client,
     ) {
         let room = room.room_id().to_string();
-        let system_time = match event.origin_server_ts.to_system_time() {
-            Some(time) => time,
-            None => return,
-        };
-        let datetime: DateTime<Utc> = system_time.into();
 
-        let sender = event.sender.to_string();
-        let message_content = event.content;
-        let message = convert_message_type(message_content.msgtype, client.homeserver().await);
+        if let Some(r) = self.rooms.rooms.iter_mut().find(|r| r.id == room) {
+            let redacted_by = r.members.display_name(event.sender.as_str());
+            r.apply_redaction(
+                event.redacts.as_str(),
+                redacted_body(&redacted_by, event.content.reason.as_deref()),
+            );
+        }
+    }
 
-        match self.rooms.rooms.iter_mut().find(|r| r.id == room) {
-            Some(r) => {
-                r.messages.add_message(
-                    datetime.format("%d/%m/%Y %T").to_string(),
-                    sender.clone(),
-                    message.clone(),
-                );
-                let current_user = match client.user_id().await {
-                    Some(user_id) => user_id.to_string(),
-                    None => "".to_string(),
-                };
-                if sender != current_user
-                    && notify_rust::Notification::new()
-                        .summary(&sender)
-                        .body(&message)
-                        .icon("matrix")
-                        .show()
-                        .is_ok()
-                {}
-            }
-            None => {}
+    /// Handles OriginalSyncReactionEvent events.
+    /// The reaction is added to the reactions of the message it reacts to.
+    /// # Arguments
+    /// * `event` - The event to handle.
+    /// * `room` - The room to handle the event in.
+    pub fn handle_matrix_reaction_event(
+        &mut self,
+        event: OriginalSyncReactionEvent,
+        room: MatrixRoom,
+    ) {
+        let room = room.room_id().to_string();
+
+        if let Some(r) = self.rooms.rooms.iter_mut().find(|r| r.id == room) {
+            r.add_reaction(
+                event.content.relates_to.event_id.as_str(),
+                &event.content.relates_to.key,
+                event.sender.as_str(),
+                event.event_id.as_str(),
+            );
         }
     }
 
@@ -439,6 +2718,13 @@ impl App {
             Some(user_id) => user_id,
             None => return,
         };
+        // Described before the member list changes, so the names are still known
+        let room_id = room.room_id().to_string();
+        if let Some(r) = self.rooms.rooms.iter_mut().find(|r| r.id == room_id) {
+            r.add_state_event(&AnySyncStateEvent::RoomMember(SyncStateEvent::Original(
+                event.clone(),
+            )));
+        }
         if event.content.membership == MembershipState::Join {
             //Check if room is already in the list
             let room_id = room.room_id().to_string();
@@ -472,6 +2758,8 @@ impl App {
                     }
                 }
             };
+            // Only the joined member or the members of a joined room are new
+            self.check_new_members().await;
         };
         if event.content.membership == MembershipState::Leave {
             let room_id = room.room_id().to_string();
@@ -484,6 +2772,7 @@ impl App {
                         }
                         self.rooms.rooms.remove(i);
                         // Reset Tab if last room is closed
+                        self.close_thread();
                         if self.current_tab == Tabs::Members || self.current_tab == Tabs::Input {
                             self.current_tab = Tabs::Room;
                         }
@@ -515,10 +2804,327 @@ impl App {
         };
     }
 
+    /// Downloads the attachment of the selected message in the background.
+    /// The file is saved to the download directory and optionally opened.
+    /// # Arguments
+    /// * `open` - Whether the file is opened with the system handler after the download.
+    pub fn download_selected_attachment(&mut self, open: bool) {
+        let attachment = match self.rooms.get_current_room() {
+            Some(room) => room
+                .messages
+                .state
+                .selected()
+                .and_then(|i| room.messages.messages[i].attachment()),
+            None => None,
+        };
+        let attachment = match attachment {
+            Some(attachment) => attachment,
+            None => {
+                self.status = Some("The selected message has no attachment".to_string());
+                return;
+            }
+        };
+
+        let client = self.client.clone();
+        let directory = self.config.downloads.directory();
+        let open_command = self.config.downloads.open_command.clone();
+        let tx = self.tx_status.clone();
+        self.status = Some(format!("Downloading {}...", attachment.name));
+        tokio::spawn(async move {
+            let status = match client.download_attachment(&attachment, &directory).await {
+                Ok(path) => {
+                    if open {
+                        match open_file(&path, &open_command) {
+                            Ok(_) => format!("Opened {}", path.display()),
+                            Err(err) => format!("Could not open {}: {}", path.display(), err),
+                        }
+                    } else {
+                        format!("Saved {}", path.display())
+                    }
+                }
+                Err(err) => format!("Could not download {}: {}", attachment.name, err),
+            };
+            if (tx.send(status).await).is_ok() {};
+        });
+    }
+
+    /// Runs a command typed into the input.
+    /// The result is shown as status.
+    /// # Arguments
+    /// * `command` - The command line starting with '/'
+    pub async fn handle_command(&mut self, command: &str) {
+        let mut args = command.split_whitespace();
+        let status = match args.next() {
+            Some("/cross-signing") => {
+                match self
+                    .client
+                    .bootstrap_cross_signing(rest_of_line(command, 1))
+                    .await
+                {
+                    Ok(_) => "Cross-signing is set up".to_string(),
+                    Err(err) => match err.uiaa_response() {
+                        Some(_) => "Type /cross-signing <password> to confirm with your password"
+                            .to_string(),
+                        None => format!("Could not set up cross-signing: {}", err),
+                    },
+                }
+            }
+            Some("/keys") => {
+                // The passphrase is the rest of the line, so it may contain spaces
+                match (args.next(), args.next(), rest_of_line(command, 3)) {
+                    (Some("export"), Some(file), Some(passphrase)) => {
+                        self.export_keys(expand_path(file), passphrase);
+                        return;
+                    }
+                    (Some("import"), Some(file), Some(passphrase)) => {
+                        self.import_keys(expand_path(file), passphrase);
+                        return;
+                    }
+                    _ => "Usage: /keys export|import <file> <passphrase>".to_string(),
+                }
+            }
+            Some("/backup") => match args.next() {
+                Some("enable") => {
+                    self.enable_backup(rest_of_line(command, 2));
+                    return;
+                }
+                Some("restore") => match rest_of_line(command, 2) {
+                    Some(secret) => {
+                        self.restore_backup(secret);
+                        return;
+                    }
+                    None => "Usage: /backup restore <recovery key or passphrase>".to_string(),
+                },
+                _ => "Usage: /backup enable [passphrase] or /backup restore <recovery key>"
+                    .to_string(),
+            },
+            Some("/upload") => match command.strip_prefix(UPLOAD_PREFIX).map(str::trim) {
+                Some(path) if !path.is_empty() => {
+                    self.upload_file(path);
+                    return;
+                }
+                _ => "Usage: /upload <path>".to_string(),
+            },
+            Some(name) => format!("Unknown command {}", name),
+            None => return,
+        };
+        self.status = Some(status);
+    }
+
+    /// Exports all room keys to a passphrase protected file in the background.
+    /// The result is shown as status.
+    /// # Arguments
+    /// * `path` - The file to write
+    /// * `passphrase` - The passphrase to encrypt the file with
+    fn export_keys(&mut self, path: PathBuf, passphrase: &str) {
+        let client = self.client.clone();
+        let passphrase = passphrase.to_string();
+        let tx = self.tx_status.clone();
+        self.status = Some(format!("Exporting room keys to {}...", path.display()));
+        tokio::spawn(async move {
+            let status = match client.export_room_keys(&path, &passphrase).await {
+                Ok(_) => format!("Exported room keys to {}", path.display()),
+                Err(err) => format!("Could not export room keys: {}", err),
+            };
+            if (tx.send(status).await).is_ok() {};
+        });
+    }
+
+    /// Imports room keys from a key export in the background
+    /// and decrypts the messages which could not be decrypted before.
+    /// The result is shown as status.
+    /// # Arguments
+    /// * `path` - The file to read
+    /// * `passphrase` - The passphrase the file is encrypted with
+    fn import_keys(&mut self, path: PathBuf, passphrase: &str) {
+        let client = self.client.clone();
+        let passphrase = passphrase.to_string();
+        let rooms = self.undecryptable_events();
+        let tx = self.tx_status.clone();
+        let tx_decrypted = self.tx_decrypted.clone();
+        self.status = Some(format!("Importing room keys from {}...", path.display()));
+        tokio::spawn(async move {
+            let status = match client.import_room_keys(&path, &passphrase).await {
+                Ok((imported, total)) => {
+                    send_decrypted(&client, rooms, &tx_decrypted).await;
+                    format!("Imported {} of {} sessions", imported, total)
+                }
+                Err(err) => format!("Could not import room keys: {}", err),
+            };
+            if (tx.send(status).await).is_ok() {};
+        });
+    }
+
+    /// Creates a key backup and uploads all room keys to it in the background.
+    /// The recovery key is shown as status.
+    /// # Arguments
+    /// * `passphrase` - The passphrase to derive the recovery key from, None for a random key
+    fn enable_backup(&mut self, passphrase: Option<&str>) {
+        let client = self.client.clone();
+        let backup_file = self.backup_file.clone();
+        let passphrase = passphrase.map(str::to_string);
+        let tx = self.tx_status.clone();
+        self.status = Some("Creating key backup...".to_string());
+        tokio::spawn(async move {
+            let status = match client
+                .enable_backup(&backup_file, passphrase.as_deref())
+                .await
+            {
+                Ok(recovery_key) => format!(
+                    "Key backup enabled, write down the recovery key: {}",
+                    recovery_key
+                ),
+                Err(err) => format!("Could not enable key backup: {}", err),
+            };
+            if (tx.send(status).await).is_ok() {};
+        });
+    }
+
+    /// Restores the room keys of the key backup in the background
+    /// and decrypts the messages which could not be decrypted before.
+    /// # Arguments
+    /// * `secret` - The recovery key or the passphrase of the backup
+    fn restore_backup(&mut self, secret: &str) {
+        let client = self.client.clone();
+        let backup_file = self.backup_file.clone();
+        let secret = secret.to_string();
+        let rooms = self.undecryptable_events();
+        let tx = self.tx_status.clone();
+        let tx_decrypted = self.tx_decrypted.clone();
+        self.status = Some("Restoring key backup...".to_string());
+        tokio::spawn(async move {
+            let status = match client.restore_backup(&backup_file, &secret).await {
+                Ok((imported, total)) => {
+                    send_decrypted(&client, rooms, &tx_decrypted).await;
+                    format!(
+                        "Restored {} of {} room keys from the backup",
+                        imported, total
+                    )
+                }
+                Err(err) => format!("Could not restore key backup: {}", err),
+            };
+            if (tx.send(status).await).is_ok() {};
+        });
+    }
+
+    /// Uploads a file to the current room in the background.
+    /// The progress is shown as status.
+    /// # Arguments
+    /// * `path` - The path of the file, may start with "~"
+    fn upload_file(&mut self, path: &str) {
+        let room = match self.rooms.get_current_room() {
+            Some(room) => room,
+            None => return,
+        };
+        let path = expand_path(path);
+        let size = match fs::metadata(&path) {
+            Ok(metadata) if metadata.is_file() => metadata.len(),
+            Ok(_) => {
+                self.status = Some(format!("{} is not a file", path.display()));
+                return;
+            }
+            Err(err) => {
+                self.status = Some(format!("Could not read {}: {}", path.display(), err));
+                return;
+            }
+        };
+
+        let client = self.client.clone();
+        let room_id = room.id.clone();
+        let tx = self.tx_status.clone();
+        self.status = Some(format!(
+            "Uploading {} ({})...",
+            path.display(),
+            format_size(size)
+        ));
+        tokio::spawn(async move {
+            let status = match client.upload_file(&room_id, &path, tx.clone()).await {
+                Ok(_) => format!("Sent {}", path.display()),
+                Err(err) => format!("Could not upload {}: {}", path.display(), err),
+            };
+            if (tx.send(status).await).is_ok() {};
+        });
+    }
+
+    /// Completes the path of an upload command in the input.
+    /// The path is extended to the longest common prefix of the matching files,
+    /// if it can not be extended the matching files are shown as status.
+    /// # Returns
+    /// * `bool` - Whether the input is an upload command with something left to complete
+    pub fn complete_upload_path(&mut self) -> bool {
+        let path = match self.input.strip_prefix(UPLOAD_PREFIX) {
+            Some(path) => path.to_string(),
+            None => return false,
+        };
+        let (directory, prefix) = match path.rfind('/') {
+            Some(i) => path.split_at(i + 1),
+            None => ("", path.as_str()),
+        };
+        let search_directory = match directory {
+            "" => PathBuf::from("."),
+            directory => expand_path(directory),
+        };
+        let entries = match fs::read_dir(search_directory) {
+            Ok(entries) => entries,
+            Err(_) => return false,
+        };
+
+        // Hidden files are only completed if the prefix asks for them
+        let mut matches: Vec<String> = entries
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| {
+                let mut name = entry.file_name().into_string().ok()?;
+                if !name.starts_with(prefix) || (name.starts_with('.') && !prefix.starts_with('.'))
+                {
+                    return None;
+                }
+                if entry.path().is_dir() {
+                    name.push('/');
+                }
+                Some(name)
+            })
+            .collect();
+        matches.sort();
+
+        let common = match matches.first() {
+            Some(first) => matches.iter().fold(first.clone(), |common, name| {
+                common
+                    .chars()
+                    .zip(name.chars())
+                    .take_while(|(a, b)| a == b)
+                    .map(|(a, _)| a)
+                    .collect()
+            }),
+            None => return false,
+        };
+        if common.len() > prefix.len() {
+            self.input = format!("{}{}{}", UPLOAD_PREFIX, directory, common);
+            self.status = None;
+        } else if matches.len() == 1 {
+            // The path is already complete
+            return false;
+        } else {
+            let mut status = matches
+                .iter()
+                .take(MAX_COMPLETIONS)
+                .cloned()
+                .collect::<Vec<String>>()
+                .join("  ");
+            if matches.len() > MAX_COMPLETIONS {
+                status.push_str(&format!("  (+{} more)", matches.len() - MAX_COMPLETIONS));
+            }
+            self.status = Some(status);
+        }
+        true
+    }
+
     /// Switches to the next tab.
     /// If room is selected:
     /// Room -> Messages -> Input -> Members -> Room -> ...
     ///
+    /// If a thread is open:
+    /// Room -> Messages -> Input -> Thread -> ThreadInput -> Members -> Room -> ...
+    ///
     /// No room selected:
     /// Room -> WelcomeScreen -> Room -> ...
     pub fn next_tab(&mut self) {
@@ -528,9 +3134,12 @@ impl App {
                 Some(_) => self.current_tab = Tabs::Input,
                 None => self.current_tab = Tabs::Room,
             },
-            Tabs::Input => {
-                self.current_tab = Tabs::Members;
-            }
+            Tabs::Input => match self.thread {
+                Some(_) => self.current_tab = Tabs::Thread,
+                None => self.current_tab = Tabs::Members,
+            },
+            Tabs::Thread => self.current_tab = Tabs::ThreadInput,
+            Tabs::ThreadInput => self.current_tab = Tabs::Members,
             Tabs::Members => {
                 match self.rooms.get_current_room() {
                     Some(r) => {
@@ -543,3 +3152,112 @@ impl App {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reaction_shortcodes_are_replaced() {
+        assert_eq!(reaction_key(":thumbsup:").as_deref(), Some("👍"));
+        assert_eq!(reaction_key(":+1:").as_deref(), Some("👍"));
+        assert_eq!(reaction_key("  :tada: ").as_deref(), Some("🎉"));
+    }
+
+    #[test]
+    fn reactions_without_shortcode_are_kept() {
+        assert_eq!(reaction_key("🚀").as_deref(), Some("🚀"));
+        assert_eq!(reaction_key("ok").as_deref(), Some("ok"));
+    }
+
+    #[test]
+    fn unknown_shortcodes_and_empty_reactions_are_rejected() {
+        assert_eq!(reaction_key(":nope:"), None);
+        assert_eq!(reaction_key(""), None);
+        assert_eq!(reaction_key("   "), None);
+    }
+
+    #[test]
+    fn rest_of_line_keeps_spaces() {
+        assert_eq!(
+            rest_of_line("/cross-signing my secret password", 1),
+            Some("my secret password")
+        );
+        assert_eq!(
+            rest_of_line("/backup restore EsTc abcd  efgh", 2),
+            Some("EsTc abcd  efgh")
+        );
+        assert_eq!(
+            rest_of_line("/keys export  ~/keys.txt  pass phrase", 3),
+            Some("pass phrase")
+        );
+    }
+
+    #[test]
+    fn rest_of_line_without_rest() {
+        assert_eq!(rest_of_line("/cross-signing", 1), None);
+        assert_eq!(rest_of_line("/cross-signing   ", 1), None);
+        assert_eq!(rest_of_line("/backup enable", 2), None);
+        assert_eq!(rest_of_line("/keys export file", 3), None);
+    }
+
+    #[test]
+    fn format_size_in_bytes() {
+        assert_eq!(format_size(0), "0 B");
+        assert_eq!(format_size(1023), "1023 B");
+    }
+
+    #[test]
+    fn format_size_with_binary_units() {
+        assert_eq!(format_size(1024), "1.0 KiB");
+        assert_eq!(format_size(1536), "1.5 KiB");
+        assert_eq!(format_size(5 * 1024 * 1024), "5.0 MiB");
+        assert_eq!(format_size(3 * 1024 * 1024 * 1024), "3.0 GiB");
+    }
+
+    #[test]
+    fn format_size_stays_in_gibibytes() {
+        assert_eq!(format_size(2048 * 1024 * 1024 * 1024), "2048.0 GiB");
+        assert_eq!(format_size(u64::MAX), "17179869184.0 GiB");
+    }
+
+    /// Returns a rendered message sent by alice
+    fn rendered_message(event_id: &str, body: &str) -> TimelineItem {
+        TimelineItem {
+            event_id: Some(event_id.to_string()),
+            transaction_id: None,
+            sender: "@alice:example.org".to_string(),
+            sender_name: "alice".to_string(),
+            timestamp: Utc::now(),
+            content: TimelineContent::Message(RoomMessageEventContent::text_plain(body)),
+            body: body.to_string(),
+            in_reply_to: None,
+            replaces: None,
+            thread_root: None,
+            edited: false,
+            reactions: Vec::new(),
+            send_state: SendState::Sent,
+            rendered: Some((80, Text::raw(body.to_string()))),
+        }
+    }
+
+    #[test]
+    fn edits_and_redactions_clear_the_rendered_body() {
+        let mut list = ScrollableMessageList::new();
+        list.add_message(rendered_message("$edited", "old"));
+        list.add_message(rendered_message("$redacted", "deleted"));
+        list.add_message(rendered_message("$other", "kept"));
+
+        list.apply_edit(
+            "$edited",
+            "@alice:example.org",
+            TimelineContent::Message(RoomMessageEventContent::text_plain("new")),
+            "new".to_string(),
+        );
+        list.apply_redaction("$redacted", "[deleted]".to_string());
+
+        assert!(list.messages[0].rendered.is_none());
+        assert!(list.messages[1].rendered.is_none());
+        assert!(list.messages[2].rendered.is_some());
+    }
+}
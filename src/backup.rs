@@ -0,0 +1,514 @@
+use aes::Aes256;
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use rand::{distributions::Alphanumeric, Rng, RngCore};
+use serde::{Deserialize, Serialize};
+use sha2::{Sha256, Sha512};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use std::collections::HashSet;
+
+use cbc::cipher::{block_padding::Pkcs7, BlockDecryptMut, BlockEncryptMut, KeyIvInit};
+use ctr::cipher::StreamCipher;
+
+/// Algorithm of the server-side key backup
+pub const BACKUP_ALGORITHM: &str = "m.megolm_backup.v1.curve25519-aes-sha2";
+
+/// Number of PBKDF2 iterations used to derive a backup key from a passphrase
+pub const PASSPHRASE_ITERATIONS: u32 = 500_000;
+
+/// First bytes of a recovery key, so it is not mistaken for another kind of key
+const RECOVERY_KEY_PREFIX: [u8; 2] = [0x8b, 0x01];
+
+/// Alphabet of base58, without 0, O, I and l which are easily confused
+const BASE58_ALPHABET: &[u8] = b"123456789ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz";
+
+const EXPORT_HEADER: &str = "-----BEGIN MEGOLM SESSION DATA-----";
+const EXPORT_FOOTER: &str = "-----END MEGOLM SESSION DATA-----";
+
+/// Version of the key export format
+const EXPORT_VERSION: u8 = 1;
+
+/// Length of the base64 lines of a key export
+const EXPORT_LINE_LENGTH: usize = 76;
+
+/// The private key of a key backup.
+/// Room keys are encrypted with its public key, so only the owner of the recovery key can read them.
+pub struct RecoveryKey {
+    /// The key as shown to the user, the x25519 secret made from it clears some of its bits
+    bytes: [u8; 32],
+}
+
+/// A room key encrypted for the key backup
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct EncryptedSessionData {
+    pub ephemeral: String,
+    pub ciphertext: String,
+    pub mac: String,
+}
+
+/// The key backup room keys are uploaded to, saved between starts
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct BackupState {
+    /// The version of the backup on the homeserver
+    pub version: String,
+    /// The public key room keys are encrypted with
+    pub public_key: String,
+    /// The ids of the room keys which are uploaded already
+    pub uploaded: HashSet<String>,
+}
+
+impl RecoveryKey {
+    /// Generate a new random recovery key
+    pub fn generate() -> RecoveryKey {
+        let mut bytes = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut bytes);
+        RecoveryKey { bytes }
+    }
+
+    /// Derive a recovery key from a passphrase
+    /// # Arguments
+    /// * `passphrase` - The passphrase
+    /// * `salt` - The salt stored with the backup
+    /// * `iterations` - The number of PBKDF2 iterations stored with the backup
+    pub fn from_passphrase(passphrase: &str, salt: &str, iterations: u32) -> RecoveryKey {
+        let mut bytes = [0u8; 32];
+        pbkdf2::pbkdf2::<Hmac<Sha512>>(
+            passphrase.as_bytes(),
+            salt.as_bytes(),
+            iterations,
+            &mut bytes,
+        );
+        RecoveryKey { bytes }
+    }
+
+    /// Parse a recovery key as shown to the user
+    /// # Arguments
+    /// * `key` - The base58 encoded key, spaces are ignored
+    /// # Returns
+    /// * `Option<RecoveryKey>` - The key, None if it is no valid recovery key
+    pub fn from_base58(key: &str) -> Option<RecoveryKey> {
+        let key: String = key.split_whitespace().collect();
+        let bytes = decode_base58(&key)?;
+        if bytes.len() != RECOVERY_KEY_PREFIX.len() + 33
+            || bytes[..RECOVERY_KEY_PREFIX.len()] != RECOVERY_KEY_PREFIX
+            || bytes.iter().fold(0, |parity, b| parity ^ b) != 0
+        {
+            return None;
+        }
+        let mut secret = [0u8; 32];
+        secret.copy_from_slice(&bytes[RECOVERY_KEY_PREFIX.len()..bytes.len() - 1]);
+        Some(RecoveryKey { bytes: secret })
+    }
+
+    /// Returns the recovery key as shown to the user, base58 encoded in groups of four characters
+    pub fn to_base58(&self) -> String {
+        let mut bytes = RECOVERY_KEY_PREFIX.to_vec();
+        bytes.extend_from_slice(&self.bytes);
+        bytes.push(bytes.iter().fold(0, |parity, b| parity ^ b));
+        encode_base58(&bytes)
+            .as_bytes()
+            .chunks(4)
+            .map(|chunk| String::from_utf8_lossy(chunk).to_string())
+            .collect::<Vec<String>>()
+            .join(" ")
+    }
+
+    /// Returns the unpadded base64 encoded public key, which is stored with the backup
+    pub fn public_key(&self) -> String {
+        encode_base64(PublicKey::from(&self.secret()).as_bytes())
+    }
+
+    /// Returns the x25519 secret of the key
+    fn secret(&self) -> StaticSecret {
+        StaticSecret::from(self.bytes)
+    }
+
+    /// Decrypt a room key of the backup
+    /// # Arguments
+    /// * `session_data` - The encrypted room key
+    /// # Returns
+    /// * `Option<String>` - The JSON of the room key, None if it can not be decrypted with this key
+    pub fn decrypt(&self, session_data: &EncryptedSessionData) -> Option<String> {
+        let ephemeral: [u8; 32] = decode_base64(&session_data.ephemeral)?.try_into().ok()?;
+        let ciphertext = decode_base64(&session_data.ciphertext)?;
+        let mac = decode_base64(&session_data.mac)?;
+
+        let shared = self.secret().diffie_hellman(&PublicKey::from(ephemeral));
+        let (aes_key, mac_key, iv) = backup_keys(shared.as_bytes())?;
+
+        // libolm computes the MAC of an empty message instead of the ciphertext,
+        // backups written by other implementations may use the ciphertext
+        if backup_mac(&mac_key, b"")? != mac && backup_mac(&mac_key, &ciphertext)? != mac {
+            return None;
+        }
+        let plaintext = cbc::Decryptor::<Aes256>::new_from_slices(&aes_key, &iv)
+            .ok()?
+            .decrypt_padded_vec_mut::<Pkcs7>(&ciphertext)
+            .ok()?;
+        String::from_utf8(plaintext).ok()
+    }
+}
+
+/// Encrypt a room key for the key backup
+/// # Arguments
+/// * `public_key` - The unpadded base64 encoded public key of the backup
+/// * `session` - The JSON of the room key
+/// # Returns
+/// * `Option<EncryptedSessionData>` - The encrypted key, None if the public key is invalid
+pub fn encrypt_session(public_key: &str, session: &str) -> Option<EncryptedSessionData> {
+    let public_key: [u8; 32] = decode_base64(public_key)?.try_into().ok()?;
+    let ephemeral = RecoveryKey::generate().secret();
+
+    let shared = ephemeral.diffie_hellman(&PublicKey::from(public_key));
+    let (aes_key, mac_key, iv) = backup_keys(shared.as_bytes())?;
+    let ciphertext = cbc::Encryptor::<Aes256>::new_from_slices(&aes_key, &iv)
+        .ok()?
+        .encrypt_padded_vec_mut::<Pkcs7>(session.as_bytes());
+
+    // Same as libolm, so other clients can verify the MAC
+    let mac = backup_mac(&mac_key, b"")?;
+    Some(EncryptedSessionData {
+        ephemeral: encode_base64(PublicKey::from(&ephemeral).as_bytes()),
+        ciphertext: encode_base64(&ciphertext),
+        mac: encode_base64(&mac),
+    })
+}
+
+/// Returns the index of the first message a room key can decrypt
+/// # Arguments
+/// * `session_key` - The base64 encoded exported room key
+pub fn first_message_index(session_key: &str) -> u32 {
+    match decode_base64(session_key) {
+        // A version byte is followed by the big endian index
+        Some(bytes) if bytes.len() >= 5 => {
+            u32::from_be_bytes([bytes[1], bytes[2], bytes[3], bytes[4]])
+        }
+        _ => 0,
+    }
+}
+
+/// Returns a random salt for deriving a key from a passphrase
+pub fn random_salt() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(32)
+        .map(char::from)
+        .collect()
+}
+
+/// Encrypt room keys in the key export format used by matrix clients
+/// # Arguments
+/// * `sessions` - The JSON array of the room keys
+/// * `passphrase` - The passphrase to encrypt the keys with
+/// * `rounds` - The number of PBKDF2 rounds used to derive the key from the passphrase
+/// # Returns
+/// * `Option<String>` - The key export, None if the cipher could not be initialized
+pub fn encrypt_key_export(sessions: &str, passphrase: &str, rounds: u32) -> Option<String> {
+    let mut salt = [0u8; 16];
+    let mut iv = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut salt);
+    rand::thread_rng().fill_bytes(&mut iv);
+    // Some implementations overflow if the 64 bit counter part of the IV is too large
+    iv[8] &= 0x7f;
+    let (aes_key, mac_key) = export_keys(passphrase, &salt, rounds);
+
+    let mut ciphertext = sessions.as_bytes().to_vec();
+    let mut cipher = ctr::Ctr128BE::<Aes256>::new_from_slices(&aes_key, &iv).ok()?;
+    cipher.apply_keystream(&mut ciphertext);
+
+    let mut data = vec![EXPORT_VERSION];
+    data.extend_from_slice(&salt);
+    data.extend_from_slice(&iv);
+    data.extend_from_slice(&rounds.to_be_bytes());
+    data.extend_from_slice(&ciphertext);
+    let mut mac = Hmac::<Sha256>::new_from_slice(&mac_key).ok()?;
+    mac.update(&data);
+    data.extend_from_slice(&mac.finalize().into_bytes());
+
+    let encoded = base64::encode(&data);
+    let mut export = vec![EXPORT_HEADER.to_string()];
+    export.extend(
+        encoded
+            .as_bytes()
+            .chunks(EXPORT_LINE_LENGTH)
+            .map(|line| String::from_utf8_lossy(line).to_string()),
+    );
+    export.push(EXPORT_FOOTER.to_string());
+    Some(export.join("\n") + "\n")
+}
+
+/// Decrypt room keys in the key export format used by matrix clients
+/// # Arguments
+/// * `export` - The content of the key export
+/// * `passphrase` - The passphrase the keys are encrypted with
+/// # Returns
+/// * `Option<String>` - The JSON array of the room keys, None if the passphrase is wrong
+pub fn decrypt_key_export(export: &str, passphrase: &str) -> Option<String> {
+    let encoded: String = export
+        .lines()
+        .map(str::trim)
+        .skip_while(|line| *line != EXPORT_HEADER)
+        .skip(1)
+        .take_while(|line| *line != EXPORT_FOOTER)
+        .collect();
+    let data = decode_base64(&encoded)?;
+    // Version, salt, IV, rounds and MAC
+    if data.len() < 1 + 16 + 16 + 4 + 32 || data[0] != EXPORT_VERSION {
+        return None;
+    }
+    let (data, mac) = data.split_at(data.len() - 32);
+    let salt = &data[1..17];
+    let iv = &data[17..33];
+    let rounds = u32::from_be_bytes([data[33], data[34], data[35], data[36]]);
+    let (aes_key, mac_key) = export_keys(passphrase, salt, rounds);
+
+    let mut expected = Hmac::<Sha256>::new_from_slice(&mac_key).ok()?;
+    expected.update(data);
+    expected.verify_slice(mac).ok()?;
+
+    let mut plaintext = data[37..].to_vec();
+    let mut cipher = ctr::Ctr128BE::<Aes256>::new_from_slices(&aes_key, iv).ok()?;
+    cipher.apply_keystream(&mut plaintext);
+    String::from_utf8(plaintext).ok()
+}
+
+/// Derive the AES key, the MAC key and the IV of a backed up room key from the shared secret
+fn backup_keys(shared: &[u8]) -> Option<([u8; 32], [u8; 32], [u8; 16])> {
+    let mut keys = [0u8; 80];
+    Hkdf::<Sha256>::new(Some(&[0u8; 32]), shared)
+        .expand(b"", &mut keys)
+        .ok()?;
+    let mut aes_key = [0u8; 32];
+    let mut mac_key = [0u8; 32];
+    let mut iv = [0u8; 16];
+    aes_key.copy_from_slice(&keys[..32]);
+    mac_key.copy_from_slice(&keys[32..64]);
+    iv.copy_from_slice(&keys[64..]);
+    Some((aes_key, mac_key, iv))
+}
+
+/// Returns the first 8 bytes of the HMAC-SHA-256 of a message, as used by the key backup
+fn backup_mac(mac_key: &[u8], message: &[u8]) -> Option<Vec<u8>> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(mac_key).ok()?;
+    mac.update(message);
+    Some(mac.finalize().into_bytes()[..8].to_vec())
+}
+
+/// Derive the AES key and the MAC key of a key export from the passphrase
+fn export_keys(passphrase: &str, salt: &[u8], rounds: u32) -> ([u8; 32], [u8; 32]) {
+    let mut keys = [0u8; 64];
+    pbkdf2::pbkdf2::<Hmac<Sha512>>(passphrase.as_bytes(), salt, rounds, &mut keys);
+    let mut aes_key = [0u8; 32];
+    let mut mac_key = [0u8; 32];
+    aes_key.copy_from_slice(&keys[..32]);
+    mac_key.copy_from_slice(&keys[32..]);
+    (aes_key, mac_key)
+}
+
+/// Encode bytes as unpadded base64, as used by matrix
+fn encode_base64(bytes: &[u8]) -> String {
+    base64::encode_config(bytes, base64::STANDARD_NO_PAD)
+}
+
+/// Decode padded or unpadded base64
+fn decode_base64(encoded: &str) -> Option<Vec<u8>> {
+    base64::decode_config(encoded.trim_end_matches('='), base64::STANDARD_NO_PAD).ok()
+}
+
+/// Encode bytes as base58
+fn encode_base58(bytes: &[u8]) -> String {
+    // Digits of the number in base 58, least significant first
+    let mut digits: Vec<u8> = Vec::new();
+    for byte in bytes {
+        let mut carry = *byte as u32;
+        for digit in digits.iter_mut() {
+            carry += (*digit as u32) << 8;
+            *digit = (carry % 58) as u8;
+            carry /= 58;
+        }
+        while carry > 0 {
+            digits.push((carry % 58) as u8);
+            carry /= 58;
+        }
+    }
+    // Leading zero bytes are kept as leading ones
+    let zeros = bytes.iter().take_while(|b| **b == 0).count();
+    let mut encoded = vec![BASE58_ALPHABET[0] as char; zeros];
+    encoded.extend(
+        digits
+            .iter()
+            .rev()
+            .map(|d| BASE58_ALPHABET[*d as usize] as char),
+    );
+    encoded.into_iter().collect()
+}
+
+/// Decode base58, None if it contains other characters than the base58 alphabet
+fn decode_base58(encoded: &str) -> Option<Vec<u8>> {
+    // Bytes of the number, least significant first
+    let mut bytes: Vec<u8> = Vec::new();
+    for c in encoded.bytes() {
+        let mut carry = BASE58_ALPHABET.iter().position(|a| *a == c)? as u32;
+        for byte in bytes.iter_mut() {
+            carry += (*byte as u32) * 58;
+            *byte = (carry & 0xff) as u8;
+            carry >>= 8;
+        }
+        while carry > 0 {
+            bytes.push((carry & 0xff) as u8);
+            carry >>= 8;
+        }
+    }
+    let zeros = encoded
+        .bytes()
+        .take_while(|c| *c == BASE58_ALPHABET[0])
+        .count();
+    let mut decoded = vec![0; zeros];
+    decoded.extend(bytes.iter().rev());
+    Some(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn base58_round_trip() {
+        assert_eq!(encode_base58(b"Hello World!"), "2NEpo7TZRRrLZSi2U");
+        assert_eq!(decode_base58("2NEpo7TZRRrLZSi2U").unwrap(), b"Hello World!");
+        assert_eq!(encode_base58(&[0, 0, 1]), "112");
+        assert_eq!(decode_base58("112").unwrap(), vec![0, 0, 1]);
+        assert_eq!(decode_base58("0OIl"), None);
+    }
+
+    #[test]
+    fn recovery_key_round_trip() {
+        let key = RecoveryKey::generate();
+        let encoded = key.to_base58();
+        // Recovery keys of all matrix clients start with "Es"
+        assert!(encoded.starts_with("Es"), "{}", encoded);
+        assert!(encoded.split(' ').all(|group| group.len() <= 4));
+        let decoded = RecoveryKey::from_base58(&encoded).unwrap();
+        assert_eq!(decoded.public_key(), key.public_key());
+    }
+
+    #[test]
+    fn recovery_key_with_wrong_parity() {
+        let key = RecoveryKey::generate();
+        let mut bytes = RECOVERY_KEY_PREFIX.to_vec();
+        bytes.extend_from_slice(&key.bytes);
+        bytes.push(bytes.iter().fold(1, |parity, b| parity ^ b));
+        assert!(RecoveryKey::from_base58(&encode_base58(&bytes)).is_none());
+        assert!(RecoveryKey::from_base58("not a key").is_none());
+    }
+
+    #[test]
+    fn passphrase_derivation() {
+        let key = RecoveryKey::from_passphrase("correct horse", "salt", 10);
+        let same = RecoveryKey::from_passphrase("correct horse", "salt", 10);
+        let other = RecoveryKey::from_passphrase("correct horse", "pepper", 10);
+        assert_eq!(key.public_key(), same.public_key());
+        assert_ne!(key.public_key(), other.public_key());
+    }
+
+    #[test]
+    fn session_round_trip() {
+        let key = RecoveryKey::generate();
+        let session = r#"{"algorithm":"m.megolm.v1.aes-sha2","session_key":"AQAAAA"}"#;
+        let encrypted = encrypt_session(&key.public_key(), session).unwrap();
+        assert!(!encrypted.ciphertext.contains('='));
+        assert_eq!(key.decrypt(&encrypted).unwrap(), session);
+
+        let other = RecoveryKey::generate();
+        assert_eq!(other.decrypt(&encrypted), None);
+
+        let mut tampered = encrypted;
+        tampered.mac = encode_base64(&[0; 8]);
+        assert_eq!(key.decrypt(&tampered), None);
+    }
+
+    #[test]
+    fn session_with_invalid_public_key() {
+        assert!(encrypt_session("too short", "{}").is_none());
+    }
+
+    #[test]
+    fn key_export_round_trip() {
+        let sessions = r#"[{"room_id":"!room:example.org","session_id":"abc"}]"#;
+        let export = encrypt_key_export(sessions, "passphrase", 10).unwrap();
+        assert!(export.starts_with(EXPORT_HEADER));
+        assert!(export.trim_end().ends_with(EXPORT_FOOTER));
+        assert!(export.lines().all(|line| line.len() <= EXPORT_LINE_LENGTH));
+        assert_eq!(decrypt_key_export(&export, "passphrase").unwrap(), sessions);
+        assert_eq!(decrypt_key_export(&export, "wrong"), None);
+        assert_eq!(decrypt_key_export("garbage", "passphrase"), None);
+    }
+
+    /// Private key of Alice from RFC 7748, section 6.1
+    const RFC7748_PRIVATE_KEY: &str =
+        "77076d0a7318a57d3c16c17251b26645df4c2f87ebc0992ab177fba51db92c2a";
+
+    fn rfc7748_key() -> RecoveryKey {
+        let mut secret = [0u8; 32];
+        for (i, byte) in secret.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&RFC7748_PRIVATE_KEY[2 * i..2 * i + 2], 16).unwrap();
+        }
+        RecoveryKey { bytes: secret }
+    }
+
+    #[test]
+    fn recovery_key_known_answer() {
+        // The recovery key and the public key were computed independently with Python
+        let encoded = "EsTc LW2K PGiF wKEA 3As5 g5c4 BXwk qeeJ ZJV8 Q9fu gUMN UE4d";
+        assert_eq!(rfc7748_key().to_base58(), encoded);
+        let key = RecoveryKey::from_base58(encoded).unwrap();
+        assert_eq!(key.bytes, rfc7748_key().bytes);
+        // Public key of Alice from RFC 7748
+        assert_eq!(
+            key.public_key(),
+            "hSDwCYkwp1R0i33ctD73Wg2/Og0mOBr066SpjqqbTmo"
+        );
+    }
+
+    #[test]
+    fn session_known_answer() {
+        // Encrypted with the private key of Bob from RFC 7748 as ephemeral key by an
+        // independent implementation using the Python cryptography package
+        let encrypted = EncryptedSessionData {
+            ephemeral: "3p7bfXt9wbTTW2HC7OQ1Nz+DQ8hbeGdNrfx+FG+IK08".to_string(),
+            ciphertext: "9lq9DgATQh0Ey5ZaVGHfoeMtfpavaYtV17dAmUZKJ5KP8WdLcGpW32I+81DhjjSTGX5OD63iQ7NwcLbdBDq3SzECzJRCNsIPle+Eac9vHVw".to_string(),
+            mac: "zpzU6BkZcNI".to_string(),
+        };
+        assert_eq!(
+            rfc7748_key().decrypt(&encrypted).unwrap(),
+            r#"{"algorithm":"m.megolm.v1.aes-sha2","sender_key":"test","session_key":"AQAAAA"}"#
+        );
+    }
+
+    #[test]
+    fn key_export_known_answer() {
+        // Test vectors of the key export of Element
+        let export = "-----BEGIN MEGOLM SESSION DATA-----\n\
+            AXNhbHRzYWx0c2FsdHNhbHSIiIiIiIiIiIiIiIiIiIiIAAAACmIRUW2OjZ3L2l6j9h0lHlV3M2dx\n\
+            cissyYBxjsfsAndErh065A8=\n\
+            -----END MEGOLM SESSION DATA-----";
+        assert_eq!(decrypt_key_export(export, "password").unwrap(), "plain");
+
+        let export = "-----BEGIN MEGOLM SESSION DATA-----\n\
+            AW1vcmVzYWx0bW9yZXNhbHT//////////wAAAAAAAAAAAAAD6KyBpe1Niv5M5NPm4ZATsJo5nghk\n\
+            KYu63a0YQ5DRhUWEKk7CcMkrKnAUiZny\n\
+            -----END MEGOLM SESSION DATA-----";
+        assert_eq!(
+            decrypt_key_export(export, "betterpassword").unwrap(),
+            "Hello, World"
+        );
+    }
+
+    #[test]
+    fn message_index_of_session_key() {
+        let key = encode_base64(&[1, 0, 0, 1, 2, 0xff]);
+        assert_eq!(first_message_index(&key), 258);
+        assert_eq!(first_message_index("!"), 0);
+    }
+}
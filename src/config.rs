@@ -0,0 +1,480 @@
+use chrono::format::{Item, StrftimeItems};
+use crossterm::event::KeyCode;
+use serde::{de, Deserialize, Deserializer};
+use url::Url;
+
+use std::{
+    fmt, fs, io,
+    path::{Path, PathBuf},
+};
+
+/// The configuration of the client.
+/// Loaded from $XDG_CONFIG_HOME/matrix_client/config.toml
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Name of the account used if none is given on the command line
+    pub default_account: Option<String>,
+    pub accounts: Vec<Account>,
+    pub notifications: Notifications,
+    pub invites: Invites,
+    pub ui: UiConfig,
+    pub downloads: Downloads,
+    pub compose: Compose,
+    pub keybindings: KeyBindings,
+}
+
+/// A matrix account
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Account {
+    pub name: String,
+    #[serde(default = "default_homeserver")]
+    pub homeserver: String,
+    pub username: String,
+    pub password_file: Option<PathBuf>,
+    pub password_command: Option<String>,
+}
+
+/// Desktop notification settings
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Notifications {
+    pub enabled: bool,
+    /// Show the message body in the notification, otherwise only the sender
+    pub show_body: bool,
+}
+
+impl Default for Notifications {
+    fn default() -> Self {
+        Notifications {
+            enabled: true,
+            show_body: true,
+        }
+    }
+}
+
+/// What to do with room invitations
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum InvitePolicy {
+    /// Accept all invitations automatically
+    Accept,
+    /// Leave invitations untouched
+    Ignore,
+}
+
+/// Room invitation settings
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Invites {
+    pub policy: InvitePolicy,
+}
+
+impl Default for Invites {
+    fn default() -> Self {
+        Invites {
+            policy: InvitePolicy::Accept,
+        }
+    }
+}
+
+/// Attachment download settings
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Downloads {
+    /// Directory attachments are saved in, defaults to the system download directory
+    pub path: Option<String>,
+    /// Command to open downloaded files with, defaults to xdg-open or open
+    pub open_command: Option<String>,
+}
+
+impl Downloads {
+    /// Returns the directory attachments are saved in
+    pub fn directory(&self) -> PathBuf {
+        match &self.path {
+            Some(path) => expand_path(path),
+            None => match dirs::download_dir() {
+                Some(dir) => dir,
+                None => PathBuf::from("."),
+            },
+        }
+    }
+}
+
+/// Settings for writing messages
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Compose {
+    /// Format messages as markdown, messages starting with "/plain " are sent as typed
+    pub markdown: bool,
+}
+
+impl Default for Compose {
+    fn default() -> Self {
+        Compose { markdown: true }
+    }
+}
+
+/// Appearance of the user interface
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct UiConfig {
+    /// strftime format of message timestamps
+    pub timestamp_format: String,
+    /// Width of the room list in percent of the terminal width
+    pub room_list_width: u16,
+    /// Height of the member list in percent of the left column
+    pub member_list_height: u16,
+}
+
+impl Default for UiConfig {
+    fn default() -> Self {
+        UiConfig {
+            timestamp_format: "%d/%m/%Y %T".to_string(),
+            room_list_width: 15,
+            member_list_height: 30,
+        }
+    }
+}
+
+/// Keys used to control the client
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct KeyBindings {
+    pub quit: Key,
+    pub next_tab: Key,
+    pub up: Key,
+    pub down: Key,
+    pub send: Key,
+    pub kick: Key,
+    pub verify: Key,
+    /// Save the attachment of the selected message
+    pub download: Key,
+    /// Save and open the attachment of the selected message
+    pub open: Key,
+    /// Reply to the selected message
+    pub reply: Key,
+    /// Edit the selected own message
+    pub edit: Key,
+    /// Delete the selected message
+    pub redact: Key,
+    /// React to the selected message or remove the own reaction
+    pub react: Key,
+    /// Open the thread of the selected message
+    pub thread: Key,
+    /// Accept or confirm in dialogs
+    pub confirm: Key,
+    /// Decline or cancel in dialogs
+    pub cancel: Key,
+}
+
+impl Default for KeyBindings {
+    fn default() -> Self {
+        KeyBindings {
+            quit: Key(KeyCode::Esc),
+            next_tab: Key(KeyCode::Tab),
+            up: Key(KeyCode::Up),
+            down: Key(KeyCode::Down),
+            send: Key(KeyCode::Enter),
+            kick: Key(KeyCode::Char('k')),
+            verify: Key(KeyCode::Char('v')),
+            download: Key(KeyCode::Char('d')),
+            open: Key(KeyCode::Char('o')),
+            reply: Key(KeyCode::Char('r')),
+            edit: Key(KeyCode::Char('e')),
+            redact: Key(KeyCode::Delete),
+            react: Key(KeyCode::Char('+')),
+            thread: Key(KeyCode::Char('t')),
+            confirm: Key(KeyCode::Char('y')),
+            cancel: Key(KeyCode::Char('n')),
+        }
+    }
+}
+
+/// A single key, written as its name ("esc", "tab", "f1", ...) or as a character in the config
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Key(pub KeyCode);
+
+impl Key {
+    /// Parse a key from its name
+    /// # Arguments
+    /// * `name` - The name of the key or a single character
+    pub fn parse(name: &str) -> Option<Key> {
+        let code = match name.to_lowercase().as_str() {
+            "esc" | "escape" => KeyCode::Esc,
+            "tab" => KeyCode::Tab,
+            "backtab" => KeyCode::BackTab,
+            "enter" | "return" => KeyCode::Enter,
+            "backspace" => KeyCode::Backspace,
+            "delete" | "del" => KeyCode::Delete,
+            "insert" | "ins" => KeyCode::Insert,
+            "up" => KeyCode::Up,
+            "down" => KeyCode::Down,
+            "left" => KeyCode::Left,
+            "right" => KeyCode::Right,
+            "home" => KeyCode::Home,
+            "end" => KeyCode::End,
+            "pageup" => KeyCode::PageUp,
+            "pagedown" => KeyCode::PageDown,
+            "space" => KeyCode::Char(' '),
+            lower => {
+                if let Some(n) = lower.strip_prefix('f').and_then(|n| n.parse::<u8>().ok()) {
+                    KeyCode::F(n)
+                } else {
+                    let mut chars = name.chars();
+                    match (chars.next(), chars.next()) {
+                        (Some(c), None) => KeyCode::Char(c),
+                        _ => return None,
+                    }
+                }
+            }
+        };
+        Some(Key(code))
+    }
+}
+
+impl<'de> Deserialize<'de> for Key {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        match Key::parse(&name) {
+            Some(key) => Ok(key),
+            None => Err(de::Error::custom(format!(
+                "\"{}\" is not a valid key name",
+                name
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            KeyCode::Esc => write!(f, "ESC"),
+            KeyCode::Tab => write!(f, "Tab"),
+            KeyCode::BackTab => write!(f, "BackTab"),
+            KeyCode::Enter => write!(f, "Enter"),
+            KeyCode::Backspace => write!(f, "Backspace"),
+            KeyCode::Delete => write!(f, "Delete"),
+            KeyCode::Insert => write!(f, "Insert"),
+            KeyCode::Up => write!(f, "Up"),
+            KeyCode::Down => write!(f, "Down"),
+            KeyCode::Left => write!(f, "Left"),
+            KeyCode::Right => write!(f, "Right"),
+            KeyCode::Home => write!(f, "Home"),
+            KeyCode::End => write!(f, "End"),
+            KeyCode::PageUp => write!(f, "PageUp"),
+            KeyCode::PageDown => write!(f, "PageDown"),
+            KeyCode::F(n) => write!(f, "F{}", n),
+            KeyCode::Char(' ') => write!(f, "Space"),
+            KeyCode::Char(c) => write!(f, "'{}'", c),
+            _ => write!(f, "?"),
+        }
+    }
+}
+
+/// Expands a leading "~" of a path to the home directory
+/// # Arguments
+/// * `path` - The path to expand
+pub fn expand_path(path: &str) -> PathBuf {
+    match (path.strip_prefix('~'), dirs::home_dir()) {
+        (Some(rest), Some(home)) if rest.is_empty() || rest.starts_with('/') => {
+            home.join(rest.trim_start_matches('/'))
+        }
+        _ => PathBuf::from(path),
+    }
+}
+
+fn default_homeserver() -> String {
+    "https://matrix.org".to_string()
+}
+
+impl Config {
+    /// Returns the default path of the config file
+    /// # Returns
+    /// * `Option<PathBuf>` - $XDG_CONFIG_HOME/matrix_client/config.toml
+    pub fn default_path() -> Option<PathBuf> {
+        dirs::config_dir().map(|dir| dir.join("matrix_client").join("config.toml"))
+    }
+
+    /// Load and validate the config file.
+    /// If no path is given and the default config file does not exist, the default config is used.
+    /// # Arguments
+    /// * `path` - The path of the config file, None for the default path
+    /// # Returns
+    /// * `Result<Config, io::Error>` - The config or an error naming the offending key
+    pub fn load(path: Option<&Path>) -> io::Result<Config> {
+        let path = match path {
+            Some(path) => path.to_path_buf(),
+            None => match Config::default_path() {
+                Some(path) if path.exists() => path,
+                _ => return Ok(Config::default()),
+            },
+        };
+
+        let content = match fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(err) => {
+                return Err(io::Error::new(
+                    err.kind(),
+                    format!("Could not read config file {}: {}", path.display(), err),
+                ));
+            }
+        };
+        let config: Config = match toml::from_str(&content) {
+            Ok(config) => config,
+            Err(err) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Invalid config file {}: {}", path.display(), err),
+                ));
+            }
+        };
+
+        match config.validate() {
+            Ok(_) => Ok(config),
+            Err((key, message)) => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Invalid config file {}: {} for key `{}`",
+                    path.display(),
+                    message,
+                    key
+                ),
+            )),
+        }
+    }
+
+    /// Check the values which can not be checked while parsing
+    /// # Returns
+    /// * `Result<(), (String, String)>` - The offending key and the error message
+    fn validate(&self) -> Result<(), (String, String)> {
+        for (i, account) in self.accounts.iter().enumerate() {
+            if Url::parse(&account.homeserver).is_err() {
+                return Err((
+                    format!("accounts[{}].homeserver", i),
+                    format!("\"{}\" is not a valid url", account.homeserver),
+                ));
+            }
+            if account.username.is_empty() {
+                return Err((
+                    format!("accounts[{}].username", i),
+                    "username must not be empty".to_string(),
+                ));
+            }
+            if self.accounts[..i].iter().any(|a| a.name == account.name) {
+                return Err((
+                    format!("accounts[{}].name", i),
+                    format!("account \"{}\" is defined twice", account.name),
+                ));
+            }
+        }
+
+        if let Some(name) = &self.default_account {
+            if self.get_account(Some(name)).is_none() {
+                return Err((
+                    "default_account".to_string(),
+                    format!("there is no account named \"{}\"", name),
+                ));
+            }
+        }
+
+        if StrftimeItems::new(&self.ui.timestamp_format).any(|item| item == Item::Error) {
+            return Err((
+                "ui.timestamp_format".to_string(),
+                format!("\"{}\" is not a valid format", self.ui.timestamp_format),
+            ));
+        }
+        if !(1..=99).contains(&self.ui.room_list_width) {
+            return Err((
+                "ui.room_list_width".to_string(),
+                "percentage must be between 1 and 99".to_string(),
+            ));
+        }
+        if !(1..=99).contains(&self.ui.member_list_height) {
+            return Err((
+                "ui.member_list_height".to_string(),
+                "percentage must be between 1 and 99".to_string(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Returns an account by name.
+    /// If no name is given, the default account or the first account is returned.
+    /// # Arguments
+    /// * `name` - The name of the account
+    pub fn get_account(&self, name: Option<&str>) -> Option<&Account> {
+        match name.or(self.default_account.as_deref()) {
+            Some(name) => self.accounts.iter().find(|a| a.name == name),
+            None => self.accounts.first(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_key_names() {
+        assert_eq!(Key::parse("esc"), Some(Key(KeyCode::Esc)));
+        assert_eq!(Key::parse("Escape"), Some(Key(KeyCode::Esc)));
+        assert_eq!(Key::parse("PageDown"), Some(Key(KeyCode::PageDown)));
+        assert_eq!(Key::parse("space"), Some(Key(KeyCode::Char(' '))));
+        assert_eq!(Key::parse("f12"), Some(Key(KeyCode::F(12))));
+    }
+
+    #[test]
+    fn parse_single_characters() {
+        assert_eq!(Key::parse("q"), Some(Key(KeyCode::Char('q'))));
+        // Characters keep their case, only names are case insensitive
+        assert_eq!(Key::parse("Q"), Some(Key(KeyCode::Char('Q'))));
+        assert_eq!(Key::parse("f"), Some(Key(KeyCode::Char('f'))));
+        assert_eq!(Key::parse("ä"), Some(Key(KeyCode::Char('ä'))));
+    }
+
+    #[test]
+    fn parse_invalid_keys() {
+        assert_eq!(Key::parse(""), None);
+        assert_eq!(Key::parse("ctrl-c"), None);
+        assert_eq!(Key::parse("f256"), None);
+    }
+
+    #[test]
+    fn invalid_key_names_the_key() {
+        let err = toml::from_str::<Config>("[keybindings]\nquit = \"nope\"\n").unwrap_err();
+        let message = err.to_string();
+        assert!(
+            message.contains("\"nope\" is not a valid key name"),
+            "{}",
+            message
+        );
+        assert!(message.contains("keybindings.quit"), "{}", message);
+    }
+
+    #[test]
+    fn display_parsed_keys() {
+        for name in ["Tab", "PageUp", "F5", "Space"] {
+            assert_eq!(Key::parse(name).unwrap().to_string(), name);
+        }
+        assert_eq!(Key::parse("x").unwrap().to_string(), "'x'");
+    }
+
+    #[test]
+    fn validate_names_the_offending_key() {
+        let config: Config = toml::from_str("[ui]\nroom_list_width = 100\n").unwrap();
+        assert_eq!(config.validate().unwrap_err().0, "ui.room_list_width");
+
+        let config: Config = toml::from_str(
+            "[[accounts]]\nname = \"work\"\nhomeserver = \"not a url\"\nusername = \"alice\"\n",
+        )
+        .unwrap();
+        assert_eq!(config.validate().unwrap_err().0, "accounts[0].homeserver");
+
+        let config: Config = toml::from_str("default_account = \"home\"\n").unwrap();
+        assert_eq!(config.validate().unwrap_err().0, "default_account");
+        assert!(Config::default().validate().is_ok());
+    }
+}
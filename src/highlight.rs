@@ -0,0 +1,180 @@
+use std::sync::OnceLock;
+
+use syntect::{
+    easy::HighlightLines,
+    highlighting::{Theme, ThemeSet},
+    parsing::SyntaxSet,
+    util::LinesWithEndings,
+};
+use tui::{
+    style::{Color, Style},
+    text::{Span, Spans, Text},
+};
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
+
+/// Theme used to highlight code blocks
+const THEME: &str = "base16-ocean.dark";
+
+/// Number of spaces a tab in a code block is replaced with
+const TAB_WIDTH: usize = 4;
+
+/// The syntax definitions, loaded on first use
+fn syntax_set() -> &'static SyntaxSet {
+    static SYNTAX_SET: OnceLock<SyntaxSet> = OnceLock::new();
+    SYNTAX_SET.get_or_init(SyntaxSet::load_defaults_newlines)
+}
+
+/// The highlighting theme, loaded on first use
+fn theme() -> &'static Theme {
+    static THEME_SET: OnceLock<ThemeSet> = OnceLock::new();
+    &THEME_SET.get_or_init(ThemeSet::load_defaults).themes[THEME]
+}
+
+/// Renders a plain message body.
+/// Fenced code blocks are drawn as code blocks, the rest is wrapped at the given width.
+/// # Arguments
+/// * `body` - The body of the message
+/// * `width` - The width the text is wrapped at
+pub fn render_plain(body: &str, width: usize) -> Text<'static> {
+    let mut text = Text::default();
+    let mut prose: Vec<&str> = Vec::new();
+    let mut code: Option<(Option<String>, Vec<&str>)> = None;
+
+    for line in body.lines() {
+        match line.trim_start().strip_prefix("```") {
+            Some(language) => match code.take() {
+                Some((language, lines)) => {
+                    text.extend(Text::from(code_block(
+                        &lines.join("\n"),
+                        language.as_deref(),
+                        width,
+                    )));
+                }
+                None => {
+                    if !prose.is_empty() {
+                        text.extend(Text::raw(textwrap::fill(&prose.join("\n"), width)));
+                        prose.clear();
+                    }
+                    let language = language.trim();
+                    code = Some((
+                        (!language.is_empty()).then(|| language.to_string()),
+                        Vec::new(),
+                    ));
+                }
+            },
+            None => match &mut code {
+                Some((_, lines)) => lines.push(line),
+                None => prose.push(line),
+            },
+        }
+    }
+
+    // A code block which is not closed ends with the message
+    if let Some((language, lines)) = code {
+        text.extend(Text::from(code_block(
+            &lines.join("\n"),
+            language.as_deref(),
+            width,
+        )));
+    }
+    if !prose.is_empty() || text.lines.is_empty() {
+        text.extend(Text::raw(textwrap::fill(&prose.join("\n"), width)));
+    }
+    text
+}
+
+/// Draws a code block in a box, highlighted by its language.
+/// Lines are not wrapped, lines wider than the box are cut off and end with "…".
+/// # Arguments
+/// * `code` - The code
+/// * `language` - The language of the code, like "rust" or "sh"
+/// * `width` - The width of the box
+pub fn code_block(code: &str, language: Option<&str>, width: usize) -> Vec<Spans<'static>> {
+    let border = Style::default().fg(Color::DarkGray);
+    let width = width.max(6);
+    // Width of the code between "│ " and " │"
+    let inner = width - 4;
+
+    let syntax_set = syntax_set();
+    let syntax = language
+        .and_then(|language| syntax_set.find_syntax_by_token(language))
+        .unwrap_or_else(|| syntax_set.find_syntax_plain_text());
+    let mut highlighter = HighlightLines::new(syntax, theme());
+
+    let label = match language {
+        Some(language) => format!("─ {} ", language),
+        None => String::new(),
+    };
+    let label = truncate(&label, width - 2);
+    let mut lines = vec![Spans::from(Span::styled(
+        format!("┌{}{}┐", label, "─".repeat(width - 2 - label.width())),
+        border,
+    ))];
+
+    let code = code.replace('\t', &" ".repeat(TAB_WIDTH)).replace('\r', "");
+    for line in LinesWithEndings::from(code.trim_end_matches('\n')) {
+        let ranges = highlighter
+            .highlight_line(line, syntax_set)
+            .unwrap_or_default();
+        // Keep one column for the indicator if the line does not fit
+        let limit = match line.trim_end_matches('\n').width() {
+            line_width if line_width > inner => inner - 1,
+            _ => inner,
+        };
+        let mut spans = vec![Span::styled("│ ", border)];
+        let mut line_width = 0;
+        let mut truncated = false;
+        for (style, part) in ranges {
+            let part = part.trim_end_matches('\n');
+            let style = Style::default().fg(Color::Rgb(
+                style.foreground.r,
+                style.foreground.g,
+                style.foreground.b,
+            ));
+            let mut chunk = String::new();
+            for c in part.chars() {
+                let char_width = c.width().unwrap_or(0);
+                if line_width + char_width > limit {
+                    truncated = true;
+                    break;
+                }
+                chunk.push(c);
+                line_width += char_width;
+            }
+            spans.push(Span::styled(chunk, style));
+            if truncated {
+                break;
+            }
+        }
+        if truncated {
+            spans.push(Span::styled("…", border));
+            line_width += 1;
+        }
+        spans.push(Span::raw(" ".repeat(inner.saturating_sub(line_width))));
+        spans.push(Span::styled(" │", border));
+        lines.push(Spans::from(spans));
+    }
+
+    lines.push(Spans::from(Span::styled(
+        format!("└{}┘", "─".repeat(width - 2)),
+        border,
+    )));
+    lines
+}
+
+/// Cuts off a text at the given width
+/// # Arguments
+/// * `text` - The text
+/// * `width` - The maximum width
+fn truncate(text: &str, width: usize) -> String {
+    let mut result = String::new();
+    let mut result_width = 0;
+    for c in text.chars() {
+        result_width += c.width().unwrap_or(0);
+        if result_width > width {
+            break;
+        }
+        result.push(c);
+    }
+    result
+}
@@ -0,0 +1,612 @@
+use crate::highlight;
+
+use tui::{
+    style::{Color, Modifier, Style},
+    text::{Span, Spans, Text},
+};
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
+
+/// A piece of a formatted message body
+enum Token {
+    /// An opening or self closing tag with its lower case name and attributes
+    Start(String, Vec<(String, String)>),
+    /// A closing tag with its lower case name
+    End(String),
+    /// Text with decoded entities
+    Text(String),
+}
+
+/// Renders the HTML subset of formatted matrix messages as styled text.
+/// Bold, italic, underlined and struck through text, links, inline code, code blocks,
+/// block quotes, lists and headings are supported, unknown tags are ignored.
+/// The text is wrapped at the given width.
+/// # Arguments
+/// * `html` - The formatted body of the message
+/// * `width` - The width the text is wrapped at
+pub fn render(html: &str, width: usize) -> Text<'static> {
+    let mut renderer = Renderer::new(width);
+    for token in tokenize(html) {
+        renderer.add(token);
+    }
+    renderer.flush();
+    Text::from(renderer.lines)
+}
+
+/// Converts the tokens of a formatted body into wrapped lines
+struct Renderer {
+    width: usize,
+    /// The finished lines
+    lines: Vec<Spans<'static>>,
+    /// The text of the current paragraph
+    fragments: Vec<(String, Style)>,
+    /// The styles of the open inline tags, with the name of the tag
+    styles: Vec<(String, Style)>,
+    /// The target and the text of the open link
+    link: Option<(String, String)>,
+    /// The counters of the open lists, None for bulleted lists
+    lists: Vec<Option<usize>>,
+    /// The bullet of the list item, added to its first line
+    bullet: Option<String>,
+    /// The width of the bullet of the current list item
+    bullet_width: usize,
+    quote_depth: usize,
+    /// Whether the text is in a code block, whose whitespace and line breaks are kept
+    preformatted: bool,
+    /// The language of the code block, given by the class of its code tag
+    language: Option<String>,
+    /// Number of open tags whose content is not shown
+    hidden: usize,
+}
+
+impl Renderer {
+    fn new(width: usize) -> Renderer {
+        Renderer {
+            width,
+            lines: Vec::new(),
+            fragments: Vec::new(),
+            styles: Vec::new(),
+            link: None,
+            lists: Vec::new(),
+            bullet: None,
+            bullet_width: 0,
+            quote_depth: 0,
+            preformatted: false,
+            language: None,
+            hidden: 0,
+        }
+    }
+
+    /// Returns the style of the text in the open inline tags
+    fn style(&self) -> Style {
+        match self.styles.last() {
+            Some((_, style)) => *style,
+            None => Style::default(),
+        }
+    }
+
+    /// Handles the next token of the formatted body
+    /// # Arguments
+    /// * `token` - The token
+    fn add(&mut self, token: Token) {
+        match token {
+            // The reply fallback is replaced by the quote of the replied message
+            Token::Start(name, _) if name == "mx-reply" => self.hidden += 1,
+            Token::End(name) if name == "mx-reply" => self.hidden = self.hidden.saturating_sub(1),
+            _ if self.hidden > 0 => {}
+            Token::Text(text) => self.add_text(text),
+            Token::Start(name, attributes) => self.start_tag(name, attributes),
+            Token::End(name) => self.end_tag(name),
+        }
+    }
+
+    /// Adds text to the current paragraph.
+    /// Whitespace is collapsed unless the text is preformatted.
+    /// # Arguments
+    /// * `text` - The text
+    fn add_text(&mut self, text: String) {
+        let text = match self.preformatted {
+            true => text,
+            false => {
+                let collapsed = text.split_whitespace().collect::<Vec<&str>>().join(" ");
+                let mut result = String::new();
+                if text.starts_with(char::is_whitespace) && !self.fragments.is_empty() {
+                    result.push(' ');
+                }
+                result.push_str(&collapsed);
+                if text.ends_with(char::is_whitespace) && !collapsed.is_empty() {
+                    result.push(' ');
+                }
+                result
+            }
+        };
+        if text.is_empty() {
+            return;
+        }
+        if let Some((_, link_text)) = &mut self.link {
+            link_text.push_str(&text);
+        }
+        let style = self.style();
+        self.fragments.push((text, style));
+    }
+
+    /// Handles an opening tag
+    /// # Arguments
+    /// * `name` - The lower case name of the tag
+    /// * `attributes` - The attributes of the tag
+    fn start_tag(&mut self, name: String, attributes: Vec<(String, String)>) {
+        let attribute = |key: &str| {
+            attributes
+                .iter()
+                .find(|(k, _)| k == key)
+                .map(|(_, value)| value.to_string())
+        };
+        match name.as_str() {
+            "br" => match self.preformatted {
+                true => self.add_text("\n".to_string()),
+                false => self.flush(),
+            },
+            "hr" => {
+                self.flush();
+                let rule = "─".repeat(self.available_width());
+                let mut line = self.prefix(false);
+                line.push(Span::styled(rule, Style::default().fg(Color::DarkGray)));
+                self.lines.push(Spans::from(line));
+            }
+            "img" => {
+                let alt = attribute("alt").unwrap_or_else(|| "image".to_string());
+                self.add_text(format!("[{}]", alt));
+            }
+            "p" | "div" | "tr" | "table" | "details" | "summary" => self.flush(),
+            "blockquote" => {
+                self.flush();
+                self.quote_depth += 1;
+            }
+            "ul" => {
+                self.flush();
+                self.lists.push(None);
+            }
+            "ol" => {
+                self.flush();
+                let start = attribute("start").and_then(|start| start.parse().ok());
+                self.lists.push(Some(start.unwrap_or(1)));
+            }
+            "li" => {
+                self.flush();
+                let bullet = match self.lists.last_mut() {
+                    Some(Some(counter)) => {
+                        let number = *counter;
+                        *counter = counter.saturating_add(1);
+                        format!("{}. ", number)
+                    }
+                    _ => "• ".to_string(),
+                };
+                self.bullet_width = bullet.width();
+                self.bullet = Some(bullet);
+            }
+            "pre" => {
+                self.flush();
+                self.preformatted = true;
+            }
+            "a" => {
+                self.link = attribute("href").map(|href| (href, String::new()));
+                self.push_style(
+                    name,
+                    Style::default()
+                        .fg(Color::Blue)
+                        .add_modifier(Modifier::UNDERLINED),
+                );
+            }
+            _ => {
+                let style = match name.as_str() {
+                    "b" | "strong" => Some(Style::default().add_modifier(Modifier::BOLD)),
+                    "i" | "em" => Some(Style::default().add_modifier(Modifier::ITALIC)),
+                    "u" | "ins" => Some(Style::default().add_modifier(Modifier::UNDERLINED)),
+                    "del" | "s" | "strike" => {
+                        Some(Style::default().add_modifier(Modifier::CROSSED_OUT))
+                    }
+                    "code" => {
+                        if self.preformatted {
+                            self.language = attribute("class").and_then(|class| {
+                                class
+                                    .split_whitespace()
+                                    .find_map(|c| c.strip_prefix("language-"))
+                                    .map(|language| language.to_string())
+                            });
+                        }
+                        Some(Style::default().fg(Color::Yellow))
+                    }
+                    "h1" | "h2" | "h3" | "h4" | "h5" | "h6" => {
+                        self.flush();
+                        Some(Style::default().add_modifier(Modifier::BOLD))
+                    }
+                    "font" | "span" => attribute("data-mx-color")
+                        .or_else(|| attribute("color"))
+                        .and_then(|color| parse_color(&color))
+                        .map(|color| Style::default().fg(color)),
+                    _ => None,
+                };
+                if let Some(style) = style {
+                    self.push_style(name, style);
+                }
+            }
+        }
+    }
+
+    /// Handles a closing tag
+    /// # Arguments
+    /// * `name` - The lower case name of the tag
+    fn end_tag(&mut self, name: String) {
+        match name.as_str() {
+            "p" | "div" | "tr" | "table" | "details" | "summary" | "li" => self.flush(),
+            "blockquote" => {
+                self.flush();
+                self.quote_depth = self.quote_depth.saturating_sub(1);
+            }
+            "ul" | "ol" => {
+                self.flush();
+                self.lists.pop();
+            }
+            "pre" => {
+                self.flush();
+                self.preformatted = false;
+                self.language = None;
+            }
+            "a" => {
+                self.pop_style(&name);
+                // Mentions and links showing their target only show the text
+                if let Some((href, text)) = self.link.take() {
+                    if text.trim() != href && !href.starts_with("https://matrix.to/") {
+                        self.fragments
+                            .push((format!(" <{}>", href), Style::default().fg(Color::DarkGray)));
+                    }
+                }
+            }
+            "h1" | "h2" | "h3" | "h4" | "h5" | "h6" => {
+                self.pop_style(&name);
+                self.flush();
+            }
+            _ => self.pop_style(&name),
+        }
+    }
+
+    /// Opens an inline style on top of the current style
+    /// # Arguments
+    /// * `name` - The name of the tag which opened the style
+    /// * `style` - The style of the tag
+    fn push_style(&mut self, name: String, style: Style) {
+        let style = self.style().patch(style);
+        self.styles.push((name, style));
+    }
+
+    /// Closes the last style opened by a tag and all styles opened after it
+    /// # Arguments
+    /// * `name` - The name of the closed tag
+    fn pop_style(&mut self, name: &str) {
+        if let Some(i) = self.styles.iter().rposition(|(n, _)| n == name) {
+            self.styles.truncate(i);
+        }
+    }
+
+    /// Returns the width of the text next to the quote and list prefix
+    fn available_width(&self) -> usize {
+        let list_width = match self.lists.len() {
+            0 => 0,
+            n => (n - 1) * 2 + self.bullet_width,
+        };
+        self.width
+            .saturating_sub(self.quote_depth * 2 + list_width)
+            .max(1)
+    }
+
+    /// Returns the quote marks and the list indentation of a line
+    /// # Arguments
+    /// * `first` - Whether the line is the first line of a paragraph, which gets the bullet
+    fn prefix(&mut self, first: bool) -> Vec<Span<'static>> {
+        let mut prefix = Vec::new();
+        if self.quote_depth > 0 {
+            prefix.push(Span::styled(
+                "│ ".repeat(self.quote_depth),
+                Style::default().fg(Color::DarkGray),
+            ));
+        }
+        if !self.lists.is_empty() {
+            let indent = "  ".repeat(self.lists.len() - 1);
+            match (first, self.bullet.take()) {
+                (true, Some(bullet)) => prefix.push(Span::raw(format!("{}{}", indent, bullet))),
+                (_, bullet) => {
+                    self.bullet = bullet;
+                    prefix.push(Span::raw(format!(
+                        "{}{}",
+                        indent,
+                        " ".repeat(self.bullet_width)
+                    )));
+                }
+            }
+        }
+        prefix
+    }
+
+    /// Wraps the current paragraph and adds it to the finished lines.
+    /// Code blocks are drawn in a box instead.
+    fn flush(&mut self) {
+        let fragments = std::mem::take(&mut self.fragments);
+        if fragments.iter().all(|(text, _)| text.trim().is_empty()) {
+            return;
+        }
+        let width = self.available_width();
+        if self.preformatted {
+            let code: String = fragments.into_iter().map(|(text, _)| text).collect();
+            for line in highlight::code_block(&code, self.language.as_deref(), width) {
+                let mut spans = self.prefix(false);
+                spans.extend(line.0);
+                self.lines.push(Spans::from(spans));
+            }
+            return;
+        }
+        for (i, line) in wrap(fragments, width).into_iter().enumerate() {
+            let mut spans = self.prefix(i == 0);
+            spans.extend(line);
+            self.lines.push(Spans::from(spans));
+        }
+    }
+}
+
+/// Wraps styled text at the given width.
+/// Words longer than the width are split.
+/// # Arguments
+/// * `fragments` - The styled text
+/// * `width` - The maximum width of a line
+fn wrap(fragments: Vec<(String, Style)>, width: usize) -> Vec<Vec<Span<'static>>> {
+    let mut lines = vec![Vec::new()];
+    let mut line_width = 0;
+    for (text, style) in fragments {
+        for word in text.split_inclusive(' ') {
+            let mut word = word;
+            if line_width > 0 && line_width + word.trim_end_matches(' ').width() > width {
+                lines.push(Vec::new());
+                line_width = 0;
+            }
+            if line_width == 0 {
+                word = word.trim_start_matches(' ');
+            }
+
+            let mut chunk = String::new();
+            for c in word.chars() {
+                let char_width = c.width().unwrap_or(0);
+                if line_width > 0 && line_width + char_width > width {
+                    if c == ' ' {
+                        continue;
+                    }
+                    if let Some(line) = lines.last_mut() {
+                        if !chunk.is_empty() {
+                            line.push(Span::styled(std::mem::take(&mut chunk), style));
+                        }
+                    }
+                    lines.push(Vec::new());
+                    line_width = 0;
+                }
+                chunk.push(c);
+                line_width += char_width;
+            }
+            if let Some(line) = lines.last_mut() {
+                if !chunk.is_empty() {
+                    line.push(Span::styled(chunk, style));
+                }
+            }
+        }
+    }
+    lines
+}
+
+/// Splits a formatted body into tags and text
+/// # Arguments
+/// * `html` - The formatted body
+fn tokenize(html: &str) -> Vec<Token> {
+    let mut tokens = Vec::new();
+    let mut rest = html;
+    while !rest.is_empty() {
+        let start = match rest.find('<') {
+            Some(start) => start,
+            None => {
+                tokens.push(Token::Text(decode_entities(rest)));
+                break;
+            }
+        };
+        if start > 0 {
+            tokens.push(Token::Text(decode_entities(&rest[..start])));
+            rest = &rest[start..];
+            continue;
+        }
+        let end = match rest.find('>') {
+            Some(end) => end,
+            None => {
+                tokens.push(Token::Text(decode_entities(rest)));
+                break;
+            }
+        };
+        let tag = &rest[1..end];
+        rest = &rest[end + 1..];
+
+        if let Some(name) = tag.strip_prefix('/') {
+            tokens.push(Token::End(name.trim().to_lowercase()));
+        } else if !tag.starts_with('!') {
+            // Comments and doctypes start with '!'
+            let tag = tag.trim_end_matches('/').trim();
+            let (name, attributes) = match tag.find(char::is_whitespace) {
+                Some(i) => (&tag[..i], parse_attributes(&tag[i..])),
+                None => (tag, Vec::new()),
+            };
+            tokens.push(Token::Start(name.to_lowercase(), attributes));
+        }
+    }
+    tokens
+}
+
+/// Parses the attributes of a tag, like `href="https://example.org" title=example`
+/// # Arguments
+/// * `input` - The part of the tag after its name
+fn parse_attributes(input: &str) -> Vec<(String, String)> {
+    let mut attributes = Vec::new();
+    let mut rest = input.trim_start();
+    while !rest.is_empty() {
+        let name_end = rest
+            .find(|c: char| c == '=' || c.is_whitespace())
+            .unwrap_or(rest.len());
+        let name = rest[..name_end].to_lowercase();
+        rest = rest[name_end..].trim_start();
+
+        let value = match rest.strip_prefix('=') {
+            Some(value) => {
+                let value = value.trim_start();
+                let (parsed, remaining) = match value.chars().next() {
+                    Some(quote) if quote == '"' || quote == '\'' => match value[1..].find(quote) {
+                        Some(end) => (&value[1..end + 1], &value[end + 2..]),
+                        None => (&value[1..], ""),
+                    },
+                    _ => {
+                        let end = value.find(char::is_whitespace).unwrap_or(value.len());
+                        (&value[..end], &value[end..])
+                    }
+                };
+                rest = remaining.trim_start();
+                decode_entities(parsed)
+            }
+            None => String::new(),
+        };
+        if !name.is_empty() {
+            attributes.push((name, value));
+        }
+    }
+    attributes
+}
+
+/// Replaces named and numeric character references like `&amp;` and `&#39;`
+/// # Arguments
+/// * `text` - The text with encoded characters
+fn decode_entities(text: &str) -> String {
+    let mut decoded = String::new();
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        decoded.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let entity = match rest.find(';') {
+            Some(end) if end <= 10 => &rest[1..end],
+            _ => {
+                decoded.push('&');
+                rest = &rest[1..];
+                continue;
+            }
+        };
+        let character = match entity {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            "nbsp" => Some('\u{a0}'),
+            _ => match entity.strip_prefix('#') {
+                Some(number) => match number.strip_prefix(|c| c == 'x' || c == 'X') {
+                    Some(hex) => u32::from_str_radix(hex, 16).ok(),
+                    None => number.parse().ok(),
+                }
+                .and_then(char::from_u32),
+                None => None,
+            },
+        };
+        match character {
+            Some(character) => {
+                decoded.push(character);
+                rest = &rest[entity.len() + 2..];
+            }
+            None => {
+                decoded.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    decoded.push_str(rest);
+    decoded
+}
+
+/// Parses a color like `#ff0000`
+/// # Arguments
+/// * `color` - The color as hex value
+fn parse_color(color: &str) -> Option<Color> {
+    let hex = color.strip_prefix('#')?;
+    if hex.len() != 6 {
+        return None;
+    }
+    let value = u32::from_str_radix(hex, 16).ok()?;
+    Some(Color::Rgb(
+        (value >> 16) as u8,
+        (value >> 8) as u8,
+        value as u8,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns the rendered lines as plain strings
+    fn lines(html: &str, width: usize) -> Vec<String> {
+        render(html, width)
+            .lines
+            .into_iter()
+            .map(|line| line.0.into_iter().map(|span| span.content).collect())
+            .collect()
+    }
+
+    #[test]
+    fn decode_named_and_numeric_entities() {
+        assert_eq!(
+            lines("a &amp; b &lt;c&gt; &#39;d&#x27; &quot;e&quot;", 80),
+            vec!["a & b <c> 'd' \"e\""]
+        );
+        // Unknown or unterminated entities are kept
+        assert_eq!(lines("&unknown; & &amp", 80), vec!["&unknown; & &amp"]);
+    }
+
+    #[test]
+    fn render_unclosed_tags() {
+        assert_eq!(lines("<b>bold <i>text", 80), vec!["bold text"]);
+        // A tag without its end is kept as text
+        assert_eq!(lines("text <a href=\"x", 80), vec!["text <a href=\"x"]);
+        let text = render("<b>bold</b> plain", 80);
+        assert_eq!(text.lines[0].0[0].style.add_modifier, Modifier::BOLD);
+        assert_eq!(text.lines[0].0[1].style, Style::default());
+    }
+
+    #[test]
+    fn strip_reply_fallback() {
+        let html =
+            "<mx-reply><blockquote><a href=\"https://matrix.to/#/!room/$event\">In reply to</a> \
+                    <b>quoted</b></blockquote></mx-reply>answer";
+        assert_eq!(lines(html, 80), vec!["answer"]);
+    }
+
+    #[test]
+    fn render_nested_lists() {
+        let html = "<ol start=\"3\"><li>three<ul><li>bullet</li></ul></li><li>four</li></ol>";
+        assert_eq!(lines(html, 80), vec!["3. three", "  • bullet", "4. four"]);
+    }
+
+    #[test]
+    fn list_counter_does_not_overflow() {
+        let html = "<ol start=\"18446744073709551615\"><li>a</li><li>b</li></ol>";
+        assert_eq!(
+            lines(html, 80),
+            vec!["18446744073709551615. a", "18446744073709551615. b"]
+        );
+    }
+
+    #[test]
+    fn wrap_wide_characters() {
+        // Each character is two columns wide and a line never gets wider than the width
+        let rendered = lines("漢字漢字漢字", 5);
+        assert_eq!(rendered, vec!["漢字", "漢字", "漢字"]);
+        assert!(rendered.iter().all(|line| line.width() <= 5));
+        // Narrower than a single character still makes progress
+        assert_eq!(lines("漢字", 1), vec!["漢", "字"]);
+    }
+}
@@ -1,10 +1,15 @@
 mod app;
+mod backup;
+mod config;
+mod highlight;
+mod html;
 mod matrix;
 mod ui;
 
-use clap::Parser;
+use clap::{Parser, Subcommand};
 
 use crate::app::App;
+use crate::config::{Account, Config};
 use crate::matrix::*;
 use crate::ui::run_ui;
 
@@ -14,28 +19,68 @@ use crossterm::{
     terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
 };
 
-use tokio::sync::mpsc;
-
 use matrix_sdk::Client;
 
-use std::io;
+use std::{env, fs, io, path::PathBuf, process};
 use tui::{backend::CrosstermBackend, Terminal};
 use url::Url;
 
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
 struct Args {
-    /// Matrix Homeserver
-    #[clap(default_value = "https://matrix.org")]
-    home_server: String,
+    /// Matrix Homeserver [default: homeserver of the account or https://matrix.org]
+    home_server: Option<String>,
 
-    /// Username
+    /// Username, overrides the account from the config file
     #[clap(short)]
-    username: String,
+    username: Option<String>,
+
+    /// Name of the account in the config file to use
+    #[clap(short, long)]
+    account: Option<String>,
 
-    /// Password
+    /// Path of the config file [default: $XDG_CONFIG_HOME/matrix_client/config.toml]
+    #[clap(short, long)]
+    config: Option<PathBuf>,
+
+    /// Password (insecure: visible in the shell history and process list).
+    /// If no password source is given, the password is prompted for.
     #[clap(short)]
-    password: String,
+    password: Option<String>,
+
+    /// Read the password from the first line of a file
+    #[clap(long)]
+    password_file: Option<PathBuf>,
+
+    /// Read the password from an environment variable
+    #[clap(long)]
+    password_env: Option<String>,
+
+    /// Read the password from the first line of a command's output, e.g. "pass show matrix"
+    #[clap(long)]
+    password_command: Option<String>,
+
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Log out the saved session and delete it
+    Logout,
+    /// Import or export room keys
+    Keys {
+        #[clap(subcommand)]
+        command: KeysCommand,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum KeysCommand {
+    /// Export all room keys to a passphrase protected file
+    Export { file: PathBuf },
+    /// Import room keys from a file exported by this or another client
+    Import { file: PathBuf },
 }
 
 #[tokio::main]
@@ -43,7 +88,37 @@ async fn main() -> io::Result<()> {
     // parse args
     let args = Args::parse();
 
-    let homeserver_url = match Url::parse(&args.home_server) {
+    // load config
+    let config = Config::load(args.config.as_deref())?;
+    // The account from the config file is only used if no username is given
+    let account = match &args.username {
+        Some(_) => None,
+        None => config.get_account(args.account.as_deref()).cloned(),
+    };
+    let username = match (&args.username, &account) {
+        (Some(username), _) => username.to_string(),
+        (None, Some(account)) => account.username.to_string(),
+        (None, None) => {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                match &args.account {
+                    Some(name) => {
+                        format!("There is no account named \"{}\" in the config file", name)
+                    }
+                    None => {
+                        "No username given. Use -u or add an account to the config file".to_string()
+                    }
+                },
+            ));
+        }
+    };
+    let home_server = match (&args.home_server, &account) {
+        (Some(home_server), _) => home_server.to_string(),
+        (None, Some(account)) => account.homeserver.to_string(),
+        (None, None) => "https://matrix.org".to_string(),
+    };
+
+    let homeserver_url = match Url::parse(&home_server) {
         Ok(url) => url,
         Err(_) => {
             return Err(io::Error::new(
@@ -53,17 +128,45 @@ async fn main() -> io::Result<()> {
         }
     };
 
+    match &args.command {
+        Some(Command::Logout) => {
+            return match Client::logout_session(homeserver_url, &username).await {
+                Ok(_) => Ok(()),
+                Err(err) => Err(io::Error::new(io::ErrorKind::Other, err.to_string())),
+            };
+        }
+        Some(Command::Keys { command }) => {
+            return run_keys_command(command, homeserver_url, &username).await;
+        }
+        None => {}
+    }
+
+    // The password is only needed if no session can be restored
+    let password = match has_session(&homeserver_url, &username).await {
+        Ok(true) => None,
+        Ok(false) => Some(read_password(&args, account.as_ref(), &username)?),
+        Err(err) => {
+            return Err(io::Error::new(io::ErrorKind::Other, err.to_string()));
+        }
+    };
+
+    let backup_file = match backup_path(&homeserver_url, &username) {
+        Ok(path) => path,
+        Err(err) => {
+            return Err(io::Error::new(io::ErrorKind::Other, err.to_string()));
+        }
+    };
+
     // initialize channel
-    let (tx_messages, rx_messages) = mpsc::channel(100);
-    let (tx_rooms, rx_rooms) = mpsc::channel(100);
+    let (senders, receivers) = event_channels();
 
     // initialize matrix client
     let client = match Client::initialize(
         homeserver_url,
-        args.username,
-        args.password,
-        tx_messages,
-        tx_rooms,
+        username,
+        password,
+        config.invites.policy,
+        senders,
     )
     .await
     {
@@ -81,8 +184,8 @@ async fn main() -> io::Result<()> {
     let mut terminal = Terminal::new(backend)?;
 
     // create app and run ui
-    let app = App::new(client).await;
-    let res = run_ui(&mut terminal, app, rx_messages, rx_rooms).await;
+    let app = App::new(client, config, backup_file).await;
+    let res = run_ui(&mut terminal, app, receivers).await;
 
     // restore terminal
     disable_raw_mode()?;
@@ -99,3 +202,99 @@ async fn main() -> io::Result<()> {
         Err(e) => Err(e),
     }
 }
+
+/// Imports or exports the room keys of the saved session
+/// # Arguments
+/// * `command` - The keys command to run
+/// * `homeserver_url` - The homeserver url
+/// * `username` - The username
+async fn run_keys_command(
+    command: &KeysCommand,
+    homeserver_url: Url,
+    username: &str,
+) -> io::Result<()> {
+    let client = match Client::open_session(homeserver_url, username).await {
+        Ok(client) => client,
+        Err(err) => return Err(io::Error::new(io::ErrorKind::Other, err.to_string())),
+    };
+    let passphrase = rpassword::prompt_password("Passphrase of the key file: ")?;
+
+    let result = match command {
+        KeysCommand::Export { file } => client
+            .export_room_keys(file, &passphrase)
+            .await
+            .map(|_| println!("Exported room keys to {}", file.display())),
+        KeysCommand::Import { file } => client
+            .import_room_keys(file, &passphrase)
+            .await
+            .map(|(imported, total)| println!("Imported {} of {} sessions", imported, total)),
+    };
+    match result {
+        Ok(_) => Ok(()),
+        Err(err) => Err(io::Error::new(io::ErrorKind::Other, err.to_string())),
+    }
+}
+
+/// Reads the password from the source given in the arguments or the account.
+/// Falls back to a hidden interactive prompt if no source is given.
+/// # Arguments
+/// * `args` - The command line arguments
+/// * `account` - The account from the config file
+/// * `username` - The username to prompt for
+/// # Returns
+/// * `Result<String, io::Error>` - The password
+fn read_password(args: &Args, account: Option<&Account>, username: &str) -> io::Result<String> {
+    if let Some(password) = &args.password {
+        return Ok(password.to_string());
+    }
+    if let Some(path) = &args.password_file {
+        let content = fs::read_to_string(path)?;
+        return Ok(first_line(&content));
+    }
+    if let Some(var) = &args.password_env {
+        return match env::var(var) {
+            Ok(password) => Ok(password),
+            Err(_) => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("Environment variable {} is not set", var),
+            )),
+        };
+    }
+    if let Some(command) = &args.password_command {
+        return run_password_command(command);
+    }
+    if let Some(account) = account {
+        if let Some(path) = &account.password_file {
+            let content = fs::read_to_string(path)?;
+            return Ok(first_line(&content));
+        }
+        if let Some(command) = &account.password_command {
+            return run_password_command(command);
+        }
+    }
+    rpassword::prompt_password(format!("Password for {}: ", username))
+}
+
+/// Runs a shell command and returns the first line of its output as password
+/// # Arguments
+/// * `command` - The command to run
+fn run_password_command(command: &str) -> io::Result<String> {
+    let output = process::Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .stdin(process::Stdio::inherit())
+        .stderr(process::Stdio::inherit())
+        .output()?;
+    if !output.status.success() {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("Password command failed: {}", output.status),
+        ));
+    }
+    Ok(first_line(&String::from_utf8_lossy(&output.stdout)))
+}
+
+/// Returns the first line of a string without the line ending
+fn first_line(content: &str) -> String {
+    content.lines().next().unwrap_or("").to_string()
+}
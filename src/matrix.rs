@@ -1,69 +1,396 @@
 use matrix_sdk::{
+    attachment::{
+        AttachmentConfig, AttachmentInfo, BaseAudioInfo, BaseFileInfo, BaseImageInfo, BaseVideoInfo,
+    },
     config::SyncSettings,
+    encryption::verification::{SasVerification, Verification, VerificationRequest},
+    media::{MediaFormat, MediaRequest},
     room::{Invited, Room},
     ruma::{
-        events::room::{
-            member::{OriginalSyncRoomMemberEvent, StrippedRoomMemberEvent},
-            message::{MessageType, OriginalSyncRoomMessageEvent, RoomMessageEventContent},
-            MediaSource,
+        api::{
+            client::{
+                account::whoami,
+                backup::{
+                    add_backup_keys, create_backup_version, get_backup_keys,
+                    get_latest_backup_info, RoomKeyBackup,
+                },
+                error::ErrorKind,
+                uiaa::{AuthData, Password, UserIdentifier},
+            },
+            error::{FromHttpResponseError, ServerError},
+        },
+        events::{
+            forwarded_room_key::ToDeviceForwardedRoomKeyEvent,
+            fully_read::FullyReadEvent,
+            key::verification::{
+                request::ToDeviceKeyVerificationRequestEvent,
+                start::{OriginalSyncKeyVerificationStartEvent, ToDeviceKeyVerificationStartEvent},
+            },
+            reaction::{
+                OriginalSyncReactionEvent, ReactionEventContent, Relation as ReactionRelation,
+            },
+            receipt::SyncReceiptEvent,
+            room::{
+                encrypted::OriginalSyncRoomEncryptedEvent,
+                member::{OriginalSyncRoomMemberEvent, StrippedRoomMemberEvent},
+                message::{
+                    EmoteMessageEventContent, InReplyTo, MessageType, OriginalSyncRoomMessageEvent,
+                    Relation, Replacement, RoomMessageEventContent, TextMessageEventContent,
+                    Thread,
+                },
+                name::OriginalSyncRoomNameEvent,
+                redaction::OriginalSyncRoomRedactionEvent,
+                topic::OriginalSyncRoomTopicEvent,
+                MediaSource,
+            },
+            room_key::ToDeviceRoomKeyEvent,
+            typing::SyncTypingEvent,
+            AnySyncRoomEvent, AnySyncStateEvent, SyncStateEvent,
         },
-        OwnedMxcUri, RoomId, UserId,
+        serde::Raw,
+        EventId, OwnedMxcUri, RoomId, TransactionId, UInt, UserId,
     },
-    Client, Error,
+    Client, Error, HttpError, LoopCtrl, RumaApiError, Session,
 };
+use mime_guess::mime;
+use serde_json::{json, value::to_raw_value, Value};
 use url::Url;
 
+use crate::backup::{
+    decrypt_key_export, encrypt_key_export, encrypt_session, first_message_index, random_salt,
+    BackupState, EncryptedSessionData, RecoveryKey, BACKUP_ALGORITHM, PASSPHRASE_ITERATIONS,
+};
+use crate::config::InvitePolicy;
+
+use std::{
+    collections::{BTreeMap, HashSet},
+    fs,
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+    process,
+};
+
 use tokio::{
-    sync::mpsc::Sender,
-    time::{sleep, Duration},
+    sync::mpsc::{self, Receiver, Sender},
+    time::{sleep, timeout, Duration},
 };
 
 use async_trait::async_trait;
 
+/// Placeholder for messages which could not be decrypted
+pub const UNABLE_TO_DECRYPT: &str = "** Unable to decrypt message **";
+
+/// Interval in which new room keys are uploaded to the key backup if no room keys are received,
+/// so the keys of the own messages are uploaded too
+const BACKUP_INTERVAL: Duration = Duration::from_secs(600);
+
+/// Number of PBKDF2 rounds of the temporary key export restored keys are imported with,
+/// the same as of the key exports of the SDK
+const TEMPORARY_EXPORT_ROUNDS: u32 = 500_000;
+
+/// A file attached to a message
+#[derive(Clone, Debug)]
+pub struct Attachment {
+    /// The file name
+    pub name: String,
+    pub source: MediaSource,
+}
+
+impl Attachment {
+    /// Returns the attachment of a file, image, audio or video message
+    /// # Arguments
+    /// * `msgtype` - The message type
+    pub fn from_message_type(msgtype: &MessageType) -> Option<Attachment> {
+        let (name, source) = match msgtype {
+            MessageType::Audio(content) => (&content.body, &content.source),
+            MessageType::File(content) => (&content.body, &content.source),
+            MessageType::Image(content) => (&content.body, &content.source),
+            MessageType::Video(content) => (&content.body, &content.source),
+            _ => return None,
+        };
+        Some(Attachment {
+            name: name.to_string(),
+            source: source.clone(),
+        })
+    }
+}
+
+/// Reader which reports how much of a file was read as status.
+/// The SDK reads the whole file before it is uploaded,
+/// so this is the progress of reading and encrypting it, not of the request.
+struct ProgressReader<R: Read> {
+    inner: R,
+    /// The file name shown in the status
+    name: String,
+    size: u64,
+    read: u64,
+    /// The last reported percentage
+    percent: u64,
+    tx: Sender<String>,
+}
+
+impl<R: Read> ProgressReader<R> {
+    /// Create a new ProgressReader
+    /// # Arguments
+    /// * `inner` - The reader of the file
+    /// * `name` - The file name shown in the status
+    /// * `size` - The size of the file in bytes
+    /// * `tx` - The channel the status is sent to
+    fn new(inner: R, name: &str, size: u64, tx: Sender<String>) -> ProgressReader<R> {
+        ProgressReader {
+            inner,
+            name: name.to_string(),
+            size,
+            read: 0,
+            percent: 0,
+            tx,
+        }
+    }
+}
+
+impl<R: Read> Read for ProgressReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.read = self.read.saturating_add(read as u64);
+        let percent = match self.size {
+            0 => 100,
+            size => self.read.min(size).saturating_mul(100) / size,
+        };
+        // Only whole percents are reported, a full channel skips the update
+        if percent > self.percent {
+            self.percent = percent;
+            if (self
+                .tx
+                .try_send(format!("Uploading {}... {}%", self.name, percent)))
+            .is_ok()
+            {};
+        }
+        Ok(read)
+    }
+}
+
+/// Verification events passed to the UI
+pub enum VerificationEvent {
+    /// Another user or device requested a verification
+    Request(VerificationRequest),
+    /// A SAS verification was started
+    Sas(SasVerification),
+}
+
+/// Channels the event handlers forward events to the UI with
+#[derive(Clone)]
+pub struct EventSenders {
+    pub messages: Sender<(OriginalSyncRoomMessageEvent, Room, Client)>,
+    /// Messages which could not be decrypted
+    pub encrypted: Sender<(OriginalSyncRoomEncryptedEvent, Room, Client)>,
+    pub rooms: Sender<(OriginalSyncRoomMemberEvent, Room, Client)>,
+    /// Topic and name changes
+    pub state: Sender<(AnySyncStateEvent, Room, Client)>,
+    pub redactions: Sender<(OriginalSyncRoomRedactionEvent, Room, Client)>,
+    pub reactions: Sender<(OriginalSyncReactionEvent, Room, Client)>,
+    /// Users typing in a room
+    pub typing: Sender<(SyncTypingEvent, Room, Client)>,
+    /// Read receipts of room members
+    pub receipts: Sender<(SyncReceiptEvent, Room, Client)>,
+    /// The own read marker, also moved by other devices
+    pub read_markers: Sender<(FullyReadEvent, Room, Client)>,
+    /// Room id, notification count and highlight count of rooms with changed unread counts
+    pub unread: Sender<(String, u64, u64)>,
+    pub verification: Sender<VerificationEvent>,
+    /// Status updates of background tasks of the client
+    pub status: Sender<String>,
+}
+
+/// The receiving ends of the event channels, polled by the UI
+pub struct EventReceivers {
+    pub messages: Receiver<(OriginalSyncRoomMessageEvent, Room, Client)>,
+    pub encrypted: Receiver<(OriginalSyncRoomEncryptedEvent, Room, Client)>,
+    pub rooms: Receiver<(OriginalSyncRoomMemberEvent, Room, Client)>,
+    pub state: Receiver<(AnySyncStateEvent, Room, Client)>,
+    pub redactions: Receiver<(OriginalSyncRoomRedactionEvent, Room, Client)>,
+    pub reactions: Receiver<(OriginalSyncReactionEvent, Room, Client)>,
+    pub typing: Receiver<(SyncTypingEvent, Room, Client)>,
+    pub receipts: Receiver<(SyncReceiptEvent, Room, Client)>,
+    pub read_markers: Receiver<(FullyReadEvent, Room, Client)>,
+    pub unread: Receiver<(String, u64, u64)>,
+    pub verification: Receiver<VerificationEvent>,
+    pub status: Receiver<String>,
+}
+
+/// Create the channels between the event handlers and the UI
+pub fn event_channels() -> (EventSenders, EventReceivers) {
+    let (tx_messages, rx_messages) = mpsc::channel(100);
+    let (tx_encrypted, rx_encrypted) = mpsc::channel(100);
+    let (tx_rooms, rx_rooms) = mpsc::channel(100);
+    let (tx_state, rx_state) = mpsc::channel(100);
+    let (tx_redactions, rx_redactions) = mpsc::channel(100);
+    let (tx_reactions, rx_reactions) = mpsc::channel(100);
+    let (tx_typing, rx_typing) = mpsc::channel(100);
+    let (tx_receipts, rx_receipts) = mpsc::channel(100);
+    let (tx_read_markers, rx_read_markers) = mpsc::channel(100);
+    let (tx_unread, rx_unread) = mpsc::channel(100);
+    let (tx_verification, rx_verification) = mpsc::channel(100);
+    let (tx_status, rx_status) = mpsc::channel(100);
+    (
+        EventSenders {
+            messages: tx_messages,
+            encrypted: tx_encrypted,
+            rooms: tx_rooms,
+            state: tx_state,
+            redactions: tx_redactions,
+            reactions: tx_reactions,
+            typing: tx_typing,
+            receipts: tx_receipts,
+            read_markers: tx_read_markers,
+            unread: tx_unread,
+            verification: tx_verification,
+            status: tx_status,
+        },
+        EventReceivers {
+            messages: rx_messages,
+            encrypted: rx_encrypted,
+            rooms: rx_rooms,
+            state: rx_state,
+            redactions: rx_redactions,
+            reactions: rx_reactions,
+            typing: rx_typing,
+            receipts: rx_receipts,
+            read_markers: rx_read_markers,
+            unread: rx_unread,
+            verification: rx_verification,
+            status: rx_status,
+        },
+    )
+}
+
 #[async_trait]
 pub trait ClientExt {
     async fn initialize(
         home_server: Url,
         username: String,
-        password: String,
-        tx_messages: Sender<(OriginalSyncRoomMessageEvent, Room, Client)>,
-        tx_rooms: Sender<(OriginalSyncRoomMemberEvent, Room, Client)>,
+        password: Option<String>,
+        invite_policy: InvitePolicy,
+        senders: EventSenders,
     ) -> Result<Client, Error>;
-    async fn send_message(&self, room_id: &str, message: &str);
+    async fn logout_session(home_server: Url, username: &str) -> Result<(), Error>;
+    async fn open_session(home_server: Url, username: &str) -> Result<Client, Error>;
+    async fn request_verification(&self, user_id: &str) -> Option<VerificationRequest>;
+    async fn is_user_verified(&self, user_id: &str) -> bool;
+    async fn bootstrap_cross_signing(&self, password: Option<&str>) -> Result<(), Error>;
+    async fn export_room_keys(&self, path: &Path, passphrase: &str) -> Result<(), Error>;
+    async fn import_room_keys(
+        &self,
+        path: &Path,
+        passphrase: &str,
+    ) -> Result<(usize, usize), Error>;
+    async fn enable_backup(
+        &self,
+        backup_file: &Path,
+        passphrase: Option<&str>,
+    ) -> Result<String, Error>;
+    async fn restore_backup(
+        &self,
+        backup_file: &Path,
+        secret: &str,
+    ) -> Result<(usize, usize), Error>;
+    async fn upload_room_keys(&self, backup_file: &Path) -> Result<usize, Error>;
+    async fn decrypt_events(
+        &self,
+        room_id: &str,
+        events: Vec<OriginalSyncRoomEncryptedEvent>,
+    ) -> Vec<AnySyncRoomEvent>;
+    async fn send_message(
+        &self,
+        room_id: &str,
+        content: RoomMessageEventContent,
+        transaction_id: &TransactionId,
+    ) -> Option<String>;
+    async fn download_attachment(
+        &self,
+        attachment: &Attachment,
+        directory: &Path,
+    ) -> Result<PathBuf, Error>;
+    async fn upload_file(
+        &self,
+        room_id: &str,
+        path: &Path,
+        progress: Sender<String>,
+    ) -> Result<String, Error>;
     async fn kick_user(&self, room_id: &str, user_id: &str);
+    async fn redact_event(
+        &self,
+        room_id: &str,
+        event_id: &str,
+        reason: Option<&str>,
+    ) -> Result<(), Error>;
+    async fn send_reaction(&self, room_id: &str, event_id: &str, key: &str) -> Result<(), Error>;
+    async fn typing_notice(&self, room_id: &str, typing: bool) -> Result<(), Error>;
+    async fn read_marker(&self, room_id: &str, event_id: &str) -> Result<(), Error>;
 }
 
 #[async_trait]
 impl ClientExt for Client {
     /// Initialize the matrix client
+    /// Restores the saved session of the account if there is one,
+    /// otherwise logs in with the password and saves the new session.
     /// # Arguments
     /// * `home_server` - The homeserver url
     /// * `username` - The username
-    /// * `password` - The password
-    /// * `tx` - The channel to send message events to
+    /// * `password` - The password, only needed if no session is saved
+    /// * `invite_policy` - Whether room invites are accepted automatically
+    /// * `senders` - The channels to send events to the UI with
     async fn initialize(
         home_server: Url,
         username: String,
-        password: String,
-        tx_messages: Sender<(OriginalSyncRoomMessageEvent, Room, Client)>,
-        tx_rooms: Sender<(OriginalSyncRoomMemberEvent, Room, Client)>,
+        password: Option<String>,
+        invite_policy: InvitePolicy,
+        senders: EventSenders,
     ) -> Result<Client, Error> {
-        let client = match Client::new(home_server).await {
-            Ok(client) => client,
-            Err(err) => {
-                return Err(Error::Http(err));
+        let session_file = session_path(&home_server, &username)?;
+        let store = store_path(&home_server, &username)?;
+        let backup_file = backup_path(&home_server, &username)?;
+
+        let client = build_client(home_server, &store).await?;
+
+        match load_session(&session_file)? {
+            Some(session) => match client.restore_login(session).await {
+                Ok(_) => (),
+                Err(err) => return Err(err),
+            },
+            None => {
+                let password = match password {
+                    Some(password) => password,
+                    None => {
+                        return Err(Error::Io(io::Error::new(
+                            io::ErrorKind::NotFound,
+                            "No saved session found, a password is required to log in",
+                        )));
+                    }
+                };
+                let response = match client
+                    .login(&username, &password, None, Some("Matrix-Tui-Client"))
+                    .await
+                {
+                    Ok(response) => response,
+                    Err(err) => return Err(err),
+                };
+                save_session(
+                    &session_file,
+                    &Session {
+                        access_token: response.access_token,
+                        user_id: response.user_id,
+                        device_id: response.device_id,
+                    },
+                )?;
             }
         };
 
-        match client
-            .login(&username, &password, None, Some("Matrix-Tui-Client"))
-            .await
-        {
-            Ok(_) => (),
-            Err(err) => return Err(err),
+        // Continue from the stored sync token, so only the changes are fetched
+        let sync_settings = match client.sync_token().await {
+            Some(token) => SyncSettings::default().token(token),
+            None => SyncSettings::default(),
         };
-
-        match client.sync_once(SyncSettings::default()).await {
+        match client.sync_once(sync_settings).await {
             Ok(_) => (),
             Err(err) => return Err(err),
         };
@@ -72,7 +399,7 @@ impl ClientExt for Client {
         // Send OriginalSyncRoomMessageEvent to message channel
         client
             .register_event_handler({
-                let tx = tx_messages.clone();
+                let tx = senders.messages.clone();
                 move |ev: OriginalSyncRoomMessageEvent, room: Room, client: Client| {
                     let tx = tx.clone();
                     async move {
@@ -82,11 +409,26 @@ impl ClientExt for Client {
             })
             .await;
 
+        // Encrypted messages are decrypted before they reach the handler above,
+        // only messages which could not be decrypted are left as encrypted events
+        // Send OriginalSyncRoomEncryptedEvent to encrypted channel
+        client
+            .register_event_handler({
+                let tx = senders.encrypted.clone();
+                move |ev: OriginalSyncRoomEncryptedEvent, room: Room, client: Client| {
+                    let tx = tx.clone();
+                    async move {
+                        if (tx.send((ev, room, client)).await).is_ok() {};
+                    }
+                }
+            })
+            .await;
+
         // Handle OriginalSyncRoomMemberEvent events
         // Send OriginalSyncRoomMemberEvent to room channel
         client
             .register_event_handler({
-                let tx = tx_rooms.clone();
+                let tx = senders.rooms.clone();
                 move |ev: OriginalSyncRoomMemberEvent, room: Room, client: Client| {
                     let tx = tx.clone();
                     async move {
@@ -96,52 +438,316 @@ impl ClientExt for Client {
             })
             .await;
 
-        // Automatically accept room invites
+        // Send OriginalSyncRoomRedactionEvent to redaction channel
         client
             .register_event_handler({
-                move |ev: StrippedRoomMemberEvent, room: Room, client: Client| async move {
-                    let user_id = match client.user_id().await {
-                        Some(user_id) => user_id,
-                        None => return,
-                    };
-                    if ev.state_key != user_id {
-                        return;
+                let tx = senders.redactions.clone();
+                move |ev: OriginalSyncRoomRedactionEvent, room: Room, client: Client| {
+                    let tx = tx.clone();
+                    async move {
+                        if (tx.send((ev, room, client)).await).is_ok() {};
+                    }
+                }
+            })
+            .await;
+
+        // Send OriginalSyncReactionEvent to reaction channel
+        client
+            .register_event_handler({
+                let tx = senders.reactions.clone();
+                move |ev: OriginalSyncReactionEvent, room: Room, client: Client| {
+                    let tx = tx.clone();
+                    async move {
+                        if (tx.send((ev, room, client)).await).is_ok() {};
+                    }
+                }
+            })
+            .await;
+
+        // Send topic and name changes to state channel
+        client
+            .register_event_handler({
+                let tx = senders.state.clone();
+                move |ev: OriginalSyncRoomTopicEvent, room: Room, client: Client| {
+                    let tx = tx.clone();
+                    async move {
+                        let ev = AnySyncStateEvent::RoomTopic(SyncStateEvent::Original(ev));
+                        if (tx.send((ev, room, client)).await).is_ok() {};
+                    }
+                }
+            })
+            .await;
+        client
+            .register_event_handler({
+                let tx = senders.state.clone();
+                move |ev: OriginalSyncRoomNameEvent, room: Room, client: Client| {
+                    let tx = tx.clone();
+                    async move {
+                        let ev = AnySyncStateEvent::RoomName(SyncStateEvent::Original(ev));
+                        if (tx.send((ev, room, client)).await).is_ok() {};
                     }
-                    if let Room::Invited(room) = room {
-                        room.accept_invitation_background();
+                }
+            })
+            .await;
+
+        // Send SyncTypingEvent to typing channel
+        client
+            .register_event_handler({
+                let tx = senders.typing.clone();
+                move |ev: SyncTypingEvent, room: Room, client: Client| {
+                    let tx = tx.clone();
+                    async move {
+                        if (tx.send((ev, room, client)).await).is_ok() {};
                     }
                 }
             })
             .await;
 
+        // Send SyncReceiptEvent to receipt channel
+        client
+            .register_event_handler({
+                let tx = senders.receipts.clone();
+                move |ev: SyncReceiptEvent, room: Room, client: Client| {
+                    let tx = tx.clone();
+                    async move {
+                        if (tx.send((ev, room, client)).await).is_ok() {};
+                    }
+                }
+            })
+            .await;
+
+        // Send FullyReadEvent to read marker channel
+        client
+            .register_event_handler({
+                let tx = senders.read_markers.clone();
+                move |ev: FullyReadEvent, room: Room, client: Client| {
+                    let tx = tx.clone();
+                    async move {
+                        if (tx.send((ev, room, client)).await).is_ok() {};
+                    }
+                }
+            })
+            .await;
+
+        // Automatically accept room invites
+        if invite_policy == InvitePolicy::Accept {
+            client
+                .register_event_handler({
+                    move |ev: StrippedRoomMemberEvent, room: Room, client: Client| async move {
+                        let user_id = match client.user_id().await {
+                            Some(user_id) => user_id,
+                            None => return,
+                        };
+                        if ev.state_key != user_id {
+                            return;
+                        }
+                        if let Room::Invited(room) = room {
+                            room.accept_invitation_background();
+                        }
+                    }
+                })
+                .await;
+        }
+
+        register_verification_handlers(&client, senders.verification).await;
+
         // Clone client to endlessly sync with server to get events
+        // and send the unread counts of each sync to unread channel
         let sync_client = client.clone();
+        let tx = senders.unread.clone();
         tokio::spawn(async move {
-            sync_client.sync(SyncSettings::default()).await;
+            sync_client
+                .sync_with_callback(SyncSettings::default(), |response| {
+                    let tx = tx.clone();
+                    async move {
+                        for (room_id, room) in response.rooms.join {
+                            let counts = room.unread_notifications;
+                            if (tx
+                                .send((
+                                    room_id.to_string(),
+                                    counts.notification_count,
+                                    counts.highlight_count,
+                                ))
+                                .await)
+                                .is_ok()
+                            {};
+                        }
+                        LoopCtrl::Continue
+                    }
+                })
+                .await;
+        });
+
+        // Notify the backup task of received room keys,
+        // a full channel already means an upload is pending
+        let (tx_keys, mut rx_keys) = mpsc::channel(1);
+        client
+            .register_event_handler({
+                let tx = tx_keys.clone();
+                move |_: ToDeviceRoomKeyEvent| {
+                    let tx = tx.clone();
+                    async move {
+                        if (tx.try_send(())).is_ok() {};
+                    }
+                }
+            })
+            .await;
+        client
+            .register_event_handler({
+                let tx = tx_keys.clone();
+                move |_: ToDeviceForwardedRoomKeyEvent| {
+                    let tx = tx.clone();
+                    async move {
+                        if (tx.try_send(())).is_ok() {};
+                    }
+                }
+            })
+            .await;
+
+        // Upload new room keys to the key backup, if it is enabled
+        let backup_client = client.clone();
+        let tx_status = senders.status.clone();
+        tokio::spawn(async move {
+            loop {
+                if let Err(err) = backup_client.upload_room_keys(&backup_file).await {
+                    if (tx_status
+                        .send(format!(
+                            "Could not upload room keys to the key backup: {}",
+                            err
+                        ))
+                        .await)
+                        .is_ok()
+                    {};
+                }
+                // Wait for received room keys, the timeout catches the keys of own messages
+                if let Ok(None) = timeout(BACKUP_INTERVAL, rx_keys.recv()).await {
+                    break;
+                }
+            }
         });
 
         return Ok(client);
     }
 
-    /// Send a message to a room
+    /// Log out the saved session of an account
+    /// Invalidates the access token on the homeserver and deletes the session file and the store.
     /// # Arguments
-    /// * `room_id` - The room id
-    /// * `message` - The message to send
-    async fn send_message(&self, room_id: &str, message: &str) {
-        if message.is_empty() {
-            return;
+    /// * `home_server` - The homeserver url
+    /// * `username` - The username
+    async fn logout_session(home_server: Url, username: &str) -> Result<(), Error> {
+        let session_file = session_path(&home_server, username)?;
+        let session = match load_session(&session_file)? {
+            Some(session) => session,
+            None => {
+                return Err(Error::Io(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "No saved session found for this account",
+                )));
+            }
+        };
+
+        let home_server_url = home_server.clone();
+        let client = match Client::new(home_server).await {
+            Ok(client) => client,
+            Err(err) => {
+                return Err(Error::Http(err));
+            }
+        };
+        client.restore_login(session).await?;
+        let result = client.logout().await;
+
+        // Delete the session even if the token was already invalid
+        remove_session(&home_server_url, username)?;
+
+        match result {
+            Ok(_) => Ok(()),
+            Err(err) => Err(Error::Http(err)),
         }
+    }
 
+    /// Open the saved session of an account without syncing
+    /// # Arguments
+    /// * `home_server` - The homeserver url
+    /// * `username` - The username
+    async fn open_session(home_server: Url, username: &str) -> Result<Client, Error> {
+        let session_file = session_path(&home_server, username)?;
+        let store = store_path(&home_server, username)?;
+        let session = match load_session(&session_file)? {
+            Some(session) => session,
+            None => {
+                return Err(Error::Io(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "No saved session found for this account, log in first",
+                )));
+            }
+        };
+
+        let client = build_client(home_server, &store).await?;
+        client.restore_login(session).await?;
+        Ok(client)
+    }
+
+    /// Send a message to a room
+    /// The message is encrypted if the room is encrypted.
+    /// # Arguments
+    /// * `room_id` - The room id
+    /// * `content` - The message to send
+    /// * `transaction_id` - The transaction id, used to match the message in the sync
+    /// # Returns
+    /// * `Option<String>` - The event id of the message, None if sending failed
+    async fn send_message(
+        &self,
+        room_id: &str,
+        content: RoomMessageEventContent,
+        transaction_id: &TransactionId,
+    ) -> Option<String> {
         let room_id = match RoomId::parse(room_id) {
             Ok(room_id) => room_id,
-            Err(_) => return,
+            Err(_) => return None,
         };
         let room = match self.get_joined_room(&room_id) {
             Some(room) => room,
-            None => return,
+            None => return None,
+        };
+        match room.send(content, Some(transaction_id)).await {
+            Ok(response) => Some(response.event_id.to_string()),
+            Err(_) => None,
+        }
+    }
+
+    /// Download an attachment into a directory
+    /// Encrypted attachments are decrypted with the key of the message.
+    /// Existing files are not overwritten, a number is added to the name instead.
+    /// # Arguments
+    /// * `attachment` - The attachment to download
+    /// * `directory` - The directory to save the file in
+    /// # Returns
+    /// * `PathBuf` - The path of the saved file
+    async fn download_attachment(
+        &self,
+        attachment: &Attachment,
+        directory: &Path,
+    ) -> Result<PathBuf, Error> {
+        let request = MediaRequest {
+            source: attachment.source.clone(),
+            format: MediaFormat::File,
+        };
+        let data = self.media().get_media_content(&request, true).await?;
+
+        fs::create_dir_all(directory)?;
+        // The name is chosen by the sender and must not escape the directory
+        let name = match Path::new(&attachment.name).file_name() {
+            Some(name) => name.to_string_lossy().to_string(),
+            None => "attachment".to_string(),
         };
-        let content = RoomMessageEventContent::text_plain(message);
-        if (room.send(content, None).await).is_ok() {};
+        let mut path = directory.join(&name);
+        let mut i = 1;
+        while path.exists() {
+            path = directory.join(format!("{}_{}", i, name));
+            i += 1;
+        }
+        fs::write(&path, data)?;
+        Ok(path)
     }
 
     /// Kick a user from a room
@@ -164,6 +770,987 @@ impl ClientExt for Client {
         };
         if (room.kick_user(user_id, None).await).is_ok() {};
     }
+
+    /// Upload a file and send it to a room as image, audio, video or file message
+    /// The MIME type is guessed from the file extension, images are sent with their dimensions.
+    /// The file is encrypted if the room is encrypted.
+    /// # Arguments
+    /// * `room_id` - The room id
+    /// * `path` - The path of the file
+    /// * `progress` - The channel the progress of reading the file is sent to as status
+    /// # Returns
+    /// * `String` - The event id of the message
+    async fn upload_file(
+        &self,
+        room_id: &str,
+        path: &Path,
+        progress: Sender<String>,
+    ) -> Result<String, Error> {
+        let room_id = match RoomId::parse(room_id) {
+            Ok(room_id) => room_id,
+            Err(_) => {
+                return Err(Error::Io(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "Invalid room id",
+                )));
+            }
+        };
+        let room = match self.get_joined_room(&room_id) {
+            Some(room) => room,
+            None => {
+                return Err(Error::Io(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "The room is not joined",
+                )));
+            }
+        };
+        let name = match path.file_name().and_then(|name| name.to_str()) {
+            Some(name) => name,
+            None => {
+                return Err(Error::Io(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "The path has no file name",
+                )));
+            }
+        };
+        let metadata = fs::metadata(path)?;
+        if !metadata.is_file() {
+            return Err(Error::Io(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "The path is not a file",
+            )));
+        }
+
+        let content_type = mime_guess::from_path(path).first_or_octet_stream();
+        let size = UInt::new(metadata.len());
+        let info = match content_type.type_() {
+            mime::IMAGE => {
+                let dimensions = imagesize::size(path).ok();
+                AttachmentInfo::Image(BaseImageInfo {
+                    height: dimensions.as_ref().and_then(|d| UInt::new(d.height as u64)),
+                    width: dimensions.as_ref().and_then(|d| UInt::new(d.width as u64)),
+                    size,
+                    blurhash: None,
+                })
+            }
+            mime::VIDEO => AttachmentInfo::Video(BaseVideoInfo {
+                duration: None,
+                height: None,
+                width: None,
+                size,
+                blurhash: None,
+            }),
+            mime::AUDIO => AttachmentInfo::Audio(BaseAudioInfo {
+                duration: None,
+                size,
+            }),
+            _ => AttachmentInfo::File(BaseFileInfo { size }),
+        };
+
+        let mut file = ProgressReader::new(fs::File::open(path)?, name, metadata.len(), progress);
+        let config = AttachmentConfig::new().info(info);
+        let response = room
+            .send_attachment(name, &content_type, &mut file, config)
+            .await?;
+        Ok(response.event_id.to_string())
+    }
+
+    /// Redact (delete) an event of a room
+    /// # Arguments
+    /// * `room_id` - The room id
+    /// * `event_id` - The event to redact
+    /// * `reason` - The reason shown to other users
+    async fn redact_event(
+        &self,
+        room_id: &str,
+        event_id: &str,
+        reason: Option<&str>,
+    ) -> Result<(), Error> {
+        let (room_id, event_id) = match (RoomId::parse(room_id), EventId::parse(event_id)) {
+            (Ok(room_id), Ok(event_id)) => (room_id, event_id),
+            _ => {
+                return Err(Error::Io(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "Invalid room or event id",
+                )));
+            }
+        };
+        let room = match self.get_joined_room(&room_id) {
+            Some(room) => room,
+            None => {
+                return Err(Error::Io(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "The room is not joined",
+                )));
+            }
+        };
+        room.redact(&event_id, reason, None).await?;
+        Ok(())
+    }
+
+    /// React to an event of a room
+    /// # Arguments
+    /// * `room_id` - The room id
+    /// * `event_id` - The event to react to
+    /// * `key` - The reaction, usually an emoji
+    async fn send_reaction(&self, room_id: &str, event_id: &str, key: &str) -> Result<(), Error> {
+        let (room_id, event_id) = match (RoomId::parse(room_id), EventId::parse(event_id)) {
+            (Ok(room_id), Ok(event_id)) => (room_id, event_id),
+            _ => {
+                return Err(Error::Io(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "Invalid room or event id",
+                )));
+            }
+        };
+        let room = match self.get_joined_room(&room_id) {
+            Some(room) => room,
+            None => {
+                return Err(Error::Io(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "The room is not joined",
+                )));
+            }
+        };
+        let content = ReactionEventContent::new(ReactionRelation::new(event_id, key.to_string()));
+        room.send(content, None).await?;
+        Ok(())
+    }
+
+    /// Start or stop the typing notice of the own user in a room.
+    /// The notice ends after a few seconds if it is not sent again.
+    /// # Arguments
+    /// * `room_id` - The room id
+    /// * `typing` - Whether the user is typing
+    async fn typing_notice(&self, room_id: &str, typing: bool) -> Result<(), Error> {
+        let room_id = match RoomId::parse(room_id) {
+            Ok(room_id) => room_id,
+            Err(_) => {
+                return Err(Error::Io(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "Invalid room id",
+                )));
+            }
+        };
+        let room = match self.get_joined_room(&room_id) {
+            Some(room) => room,
+            None => {
+                return Err(Error::Io(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "The room is not joined",
+                )));
+            }
+        };
+        room.typing_notice(typing).await?;
+        Ok(())
+    }
+
+    /// Move the read marker and the read receipt of the own user to an event
+    /// # Arguments
+    /// * `room_id` - The room id
+    /// * `event_id` - The last read event
+    async fn read_marker(&self, room_id: &str, event_id: &str) -> Result<(), Error> {
+        let (room_id, event_id) = match (RoomId::parse(room_id), EventId::parse(event_id)) {
+            (Ok(room_id), Ok(event_id)) => (room_id, event_id),
+            _ => {
+                return Err(Error::Io(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "Invalid room or event id",
+                )));
+            }
+        };
+        let room = match self.get_joined_room(&room_id) {
+            Some(room) => room,
+            None => {
+                return Err(Error::Io(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "The room is not joined",
+                )));
+            }
+        };
+        room.read_marker(&event_id, Some(&event_id)).await?;
+        Ok(())
+    }
+
+    /// Request a verification with a user
+    /// The user needs to have set up cross-signing.
+    /// # Arguments
+    /// * `user_id` - The user to verify
+    /// # Returns
+    /// * `Option<VerificationRequest>` - The request or None if the user can not be verified
+    async fn request_verification(&self, user_id: &str) -> Option<VerificationRequest> {
+        let user_id = match <&UserId>::try_from(user_id) {
+            Ok(user_id) => user_id,
+            Err(_) => return None,
+        };
+        let identity = match self.encryption().get_user_identity(user_id).await {
+            Ok(Some(identity)) => identity,
+            _ => return None,
+        };
+        match identity.request_verification().await {
+            Ok(request) => Some(request),
+            Err(_) => None,
+        }
+    }
+
+    /// Checks if the identity of a user is verified
+    /// # Arguments
+    /// * `user_id` - The user to check
+    async fn is_user_verified(&self, user_id: &str) -> bool {
+        let user_id = match <&UserId>::try_from(user_id) {
+            Ok(user_id) => user_id,
+            Err(_) => return false,
+        };
+        match self.encryption().get_user_identity(user_id).await {
+            Ok(Some(identity)) => identity.verified(),
+            _ => false,
+        }
+    }
+
+    /// Create and upload the cross-signing keys of the own user
+    /// Most homeservers require the password to upload the keys,
+    /// without it the request fails with an uiaa response.
+    /// # Arguments
+    /// * `password` - The password of the own user
+    async fn bootstrap_cross_signing(&self, password: Option<&str>) -> Result<(), Error> {
+        let err = match self.encryption().bootstrap_cross_signing(None).await {
+            Ok(_) => return Ok(()),
+            Err(err) => err,
+        };
+        let (response, password) = match (err.uiaa_response(), password) {
+            (Some(response), Some(password)) => (response, password),
+            _ => return Err(err),
+        };
+        let user_id = match self.user_id().await {
+            Some(user_id) => user_id,
+            None => return Err(err),
+        };
+
+        let mut auth = Password::new(
+            UserIdentifier::UserIdOrLocalpart(user_id.as_str()),
+            password,
+        );
+        auth.session = response.session.as_deref();
+        self.encryption()
+            .bootstrap_cross_signing(Some(AuthData::Password(auth)))
+            .await
+    }
+
+    /// Export all room keys to a passphrase protected file
+    /// The file uses the key export format of other matrix clients.
+    /// # Arguments
+    /// * `path` - The file to write
+    /// * `passphrase` - The passphrase to encrypt the file with
+    async fn export_room_keys(&self, path: &Path, passphrase: &str) -> Result<(), Error> {
+        self.encryption()
+            .export_keys(path.to_path_buf(), passphrase, |_| true)
+            .await
+    }
+
+    /// Import room keys from a passphrase protected key export
+    /// # Arguments
+    /// * `path` - The file to read
+    /// * `passphrase` - The passphrase the file is encrypted with
+    /// # Returns
+    /// * `(usize, usize)` - The number of imported sessions and of sessions in the file
+    async fn import_room_keys(
+        &self,
+        path: &Path,
+        passphrase: &str,
+    ) -> Result<(usize, usize), Error> {
+        match self
+            .encryption()
+            .import_keys(path.to_path_buf(), passphrase)
+            .await
+        {
+            Ok(result) => Ok((result.imported_count, result.total_count)),
+            Err(err) => Err(Error::Io(io::Error::new(
+                io::ErrorKind::InvalidData,
+                err.to_string(),
+            ))),
+        }
+    }
+
+    /// Create a new key backup on the homeserver and upload all room keys to it
+    /// Without a passphrase a random recovery key is generated,
+    /// with a passphrase the recovery key is derived from it.
+    /// # Arguments
+    /// * `backup_file` - The file the state of the backup is saved in
+    /// * `passphrase` - The passphrase to derive the recovery key from
+    /// # Returns
+    /// * `String` - The recovery key, needed to restore the backup
+    async fn enable_backup(
+        &self,
+        backup_file: &Path,
+        passphrase: Option<&str>,
+    ) -> Result<String, Error> {
+        // The salt and the iterations let other clients derive the key from the passphrase
+        let (key, mut auth_data) = match passphrase {
+            Some(passphrase) => {
+                let salt = random_salt();
+                (
+                    RecoveryKey::from_passphrase(passphrase, &salt, PASSPHRASE_ITERATIONS),
+                    json!({
+                        "private_key_salt": salt,
+                        "private_key_iterations": PASSPHRASE_ITERATIONS,
+                    }),
+                )
+            }
+            None => (RecoveryKey::generate(), json!({})),
+        };
+        auth_data["public_key"] = json!(key.public_key());
+        // The signing keys of the device and of the cross-signing identity are kept in the
+        // crypto store, the SDK offers no way to sign other data with them. Other clients show
+        // the backup as not trusted, restoring checks the public key against the recovery key.
+        auth_data["signatures"] = json!({});
+
+        let algorithm = json!({
+            "algorithm": BACKUP_ALGORITHM,
+            "auth_data": auth_data,
+        });
+        let request =
+            create_backup_version::v3::Request::new(Raw::from_json(to_raw_value(&algorithm)?));
+        let response = self.send(request, None).await.map_err(Error::Http)?;
+
+        save_backup_state(
+            backup_file,
+            &BackupState {
+                version: response.version,
+                public_key: key.public_key(),
+                uploaded: HashSet::new(),
+            },
+        )?;
+        self.upload_room_keys(backup_file).await?;
+        Ok(key.to_base58())
+    }
+
+    /// Download and import the room keys of the latest key backup
+    /// New room keys are uploaded to the restored backup afterwards.
+    /// # Arguments
+    /// * `backup_file` - The file the state of the backup is saved in
+    /// * `secret` - The recovery key or the passphrase of the backup
+    /// # Returns
+    /// * `(usize, usize)` - The number of imported room keys and of room keys in the backup
+    async fn restore_backup(
+        &self,
+        backup_file: &Path,
+        secret: &str,
+    ) -> Result<(usize, usize), Error> {
+        let info = self
+            .send(get_latest_backup_info::v3::Request::new(), None)
+            .await
+            .map_err(Error::Http)?;
+        let algorithm = info.algorithm.deserialize_as::<Value>()?;
+        if algorithm["algorithm"] != BACKUP_ALGORITHM {
+            return Err(Error::Io(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("Unsupported backup algorithm {}", algorithm["algorithm"]),
+            )));
+        }
+        let auth_data = &algorithm["auth_data"];
+
+        let key = match (
+            RecoveryKey::from_base58(secret),
+            auth_data["private_key_salt"].as_str(),
+            auth_data["private_key_iterations"].as_u64(),
+        ) {
+            (Some(key), _, _) => key,
+            (None, Some(salt), Some(iterations)) => {
+                RecoveryKey::from_passphrase(secret, salt, iterations as u32)
+            }
+            (None, _, _) => {
+                return Err(Error::Io(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "Not a valid recovery key",
+                )));
+            }
+        };
+        let public_key = key.public_key();
+        if auth_data["public_key"] != public_key.as_str() {
+            return Err(Error::Io(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "The recovery key or passphrase does not match the key backup",
+            )));
+        }
+
+        let response = self
+            .send(get_backup_keys::v3::Request::new(&info.version), None)
+            .await
+            .map_err(Error::Http)?;
+        let mut sessions = Vec::new();
+        let mut uploaded = HashSet::new();
+        for (room_id, backup) in response.rooms {
+            for (session_id, data) in backup.sessions {
+                // Keys which can not be decrypted are skipped, the others are still restored
+                let session = data
+                    .deserialize_as::<Value>()
+                    .ok()
+                    .and_then(|data| {
+                        serde_json::from_value::<EncryptedSessionData>(data["session_data"].clone())
+                            .ok()
+                    })
+                    .and_then(|session_data| key.decrypt(&session_data))
+                    .and_then(|session| serde_json::from_str::<Value>(&session).ok());
+                if let Some(mut session) = session {
+                    session["room_id"] = json!(room_id);
+                    session["session_id"] = json!(session_id);
+                    sessions.push(session);
+                    uploaded.insert(session_id);
+                }
+            }
+        }
+
+        // The SDK only imports keys from the key export format,
+        // so the keys are passed through a temporary file with a random passphrase
+        let passphrase = random_salt();
+        let key_file = TemporaryKeyFile::create(backup_file)?;
+        let export = match encrypt_key_export(
+            &serde_json::to_string(&sessions)?,
+            &passphrase,
+            TEMPORARY_EXPORT_ROUNDS,
+        ) {
+            Some(export) => export,
+            None => {
+                return Err(Error::Io(io::Error::new(
+                    io::ErrorKind::Other,
+                    "Could not encrypt the restored room keys",
+                )));
+            }
+        };
+        fs::write(&key_file.path, export)?;
+        let counts = self.import_room_keys(&key_file.path, &passphrase).await?;
+        drop(key_file);
+
+        save_backup_state(
+            backup_file,
+            &BackupState {
+                version: info.version,
+                public_key,
+                uploaded,
+            },
+        )?;
+        Ok(counts)
+    }
+
+    /// Upload the room keys which are not in the key backup yet
+    /// Nothing is uploaded if the backup is not enabled.
+    /// # Arguments
+    /// * `backup_file` - The file the state of the backup is saved in
+    /// # Returns
+    /// * `usize` - The number of uploaded room keys
+    async fn upload_room_keys(&self, backup_file: &Path) -> Result<usize, Error> {
+        let mut state = match load_backup_state(backup_file)? {
+            Some(state) => state,
+            None => return Ok(0),
+        };
+
+        // The SDK only exports keys to a file, it is deleted right after reading it
+        let passphrase = random_salt();
+        let key_file = TemporaryKeyFile::create(backup_file)?;
+        let uploaded = state.uploaded.clone();
+        self.encryption()
+            .export_keys(key_file.path.clone(), &passphrase, move |session| {
+                !uploaded.contains(session.session_id())
+            })
+            .await?;
+        let export = fs::read_to_string(&key_file.path)?;
+        drop(key_file);
+        let sessions: Vec<Value> = match decrypt_key_export(&export, &passphrase) {
+            Some(sessions) => serde_json::from_str(&sessions)?,
+            None => {
+                return Err(Error::Io(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Could not read the exported room keys",
+                )));
+            }
+        };
+
+        let mut rooms = BTreeMap::new();
+        let mut session_ids = Vec::new();
+        // The keys of the verified devices of each room, looked up once per room
+        let mut verified_keys = BTreeMap::new();
+        for mut session in sessions {
+            let (room_id, session_id) = match (
+                session["room_id"].as_str().map(RoomId::parse),
+                session["session_id"].as_str(),
+            ) {
+                (Some(Ok(room_id)), Some(session_id)) => (room_id, session_id.to_string()),
+                _ => continue,
+            };
+            // The room and the session are the keys of the backup, not part of the encrypted data
+            if let Some(session) = session.as_object_mut() {
+                session.remove("room_id");
+                session.remove("session_id");
+            }
+            let session_data = match encrypt_session(&state.public_key, &session.to_string()) {
+                Some(session_data) => session_data,
+                None => {
+                    return Err(Error::Io(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "The public key of the key backup is invalid",
+                    )));
+                }
+            };
+            let forwarded_count = session["forwarding_curve25519_key_chain"]
+                .as_array()
+                .map_or(0, |chain| chain.len());
+            if !verified_keys.contains_key(&room_id) {
+                let keys = verified_device_keys(self, &room_id).await;
+                verified_keys.insert(room_id.clone(), keys);
+            }
+            // A forwarded key can not be vouched for, it was not received from its sender
+            let is_verified = forwarded_count == 0
+                && session["sender_key"]
+                    .as_str()
+                    .map_or(false, |key| verified_keys[&room_id].contains(key));
+            let key_data = json!({
+                "first_message_index": first_message_index(
+                    session["session_key"].as_str().unwrap_or("")
+                ),
+                "forwarded_count": forwarded_count,
+                "is_verified": is_verified,
+                "session_data": session_data,
+            });
+            rooms
+                .entry(room_id)
+                .or_insert_with(|| RoomKeyBackup::new(BTreeMap::new()))
+                .sessions
+                .insert(session_id.clone(), Raw::from_json(to_raw_value(&key_data)?));
+            session_ids.push(session_id);
+        }
+        if session_ids.is_empty() {
+            return Ok(0);
+        }
+
+        let request = add_backup_keys::v3::Request::new(&state.version, rooms);
+        self.send(request, None).await.map_err(Error::Http)?;
+        let count = session_ids.len();
+        state.uploaded.extend(session_ids);
+        save_backup_state(backup_file, &state)?;
+        Ok(count)
+    }
+
+    /// Decrypt messages again which could not be decrypted before,
+    /// used after new room keys were imported
+    /// # Arguments
+    /// * `room_id` - The room of the messages
+    /// * `events` - The encrypted messages
+    /// # Returns
+    /// * `Vec<AnySyncRoomEvent>` - The messages which could be decrypted now
+    async fn decrypt_events(
+        &self,
+        room_id: &str,
+        events: Vec<OriginalSyncRoomEncryptedEvent>,
+    ) -> Vec<AnySyncRoomEvent> {
+        let room = match <&RoomId>::try_from(room_id) {
+            Ok(room_id) => match self.get_room(room_id) {
+                Some(room) => room,
+                None => return Vec::new(),
+            },
+            Err(_) => return Vec::new(),
+        };
+        let mut decrypted = Vec::new();
+        for event in events {
+            if let Ok(event) = room.decrypt_event(&event).await {
+                if let Ok(event) = event.event.deserialize_as::<AnySyncRoomEvent>() {
+                    decrypted.push(event);
+                }
+            }
+        }
+        decrypted
+    }
+}
+
+/// Returns the curve25519 keys of the verified devices of the members of a room
+/// # Arguments
+/// * `client` - The client
+/// * `room_id` - The room
+async fn verified_device_keys(client: &Client, room_id: &RoomId) -> HashSet<String> {
+    let mut keys = HashSet::new();
+    let room = match client.get_room(room_id) {
+        Some(room) => room,
+        None => return keys,
+    };
+    let members = match room.members_no_sync().await {
+        Ok(members) => members,
+        Err(_) => return keys,
+    };
+    for member in members {
+        let devices = match client.encryption().get_user_devices(member.user_id()).await {
+            Ok(devices) => devices,
+            Err(_) => continue,
+        };
+        for device in devices.devices() {
+            if device.verified() {
+                if let Some(key) = device.curve25519_key() {
+                    keys.insert(key.to_base64());
+                }
+            }
+        }
+    }
+    keys
+}
+
+/// Build a client which keeps its state and encryption keys in a store
+/// # Arguments
+/// * `home_server` - The homeserver url
+/// * `store` - The directory of the store
+async fn build_client(home_server: Url, store: &Path) -> Result<Client, Error> {
+    // The store is opened without a passphrase, so the encryption keys in it are only
+    // protected by the permissions of the directory, which only the user may access
+    let mut dir_builder = fs::DirBuilder::new();
+    dir_builder.recursive(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::DirBuilderExt;
+        dir_builder.mode(0o700);
+    }
+    dir_builder.create(store)?;
+    // The mode only applies to new directories, an existing store may still be readable by others
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(store, fs::Permissions::from_mode(0o700))?;
+    }
+
+    // The state store keeps rooms, members and timelines between starts,
+    // the crypto store in the same directory keeps the encryption keys
+    let builder = match Client::builder()
+        .homeserver_url(home_server)
+        .sled_store(store, None)
+    {
+        Ok(builder) => builder,
+        Err(err) => {
+            return Err(Error::Io(io::Error::new(
+                io::ErrorKind::Other,
+                format!("Could not open store {}: {}", store.display(), err),
+            )));
+        }
+    };
+    match builder.build().await {
+        Ok(client) => Ok(client),
+        Err(err) => Err(Error::Io(io::Error::new(
+            io::ErrorKind::Other,
+            err.to_string(),
+        ))),
+    }
+}
+
+/// Register the event handlers of the verification flow
+/// Requests and started SAS verifications are sent to the UI,
+/// SAS verifications started by the other side are only accepted once the user confirms them.
+/// # Arguments
+/// * `client` - The client to register the handlers on
+/// * `tx` - The channel to send verification events to
+async fn register_verification_handlers(client: &Client, tx: Sender<VerificationEvent>) {
+    // Requests from other devices of the own user
+    client
+        .register_event_handler({
+            let tx = tx.clone();
+            move |ev: ToDeviceKeyVerificationRequestEvent, client: Client| {
+                let tx = tx.clone();
+                async move {
+                    if let Some(request) = client
+                        .encryption()
+                        .get_verification_request(&ev.sender, ev.content.transaction_id.as_str())
+                        .await
+                    {
+                        if (tx.send(VerificationEvent::Request(request)).await).is_ok() {};
+                    }
+                }
+            }
+        })
+        .await;
+
+    // Requests from other users are sent as room messages
+    client
+        .register_event_handler({
+            let tx = tx.clone();
+            move |ev: OriginalSyncRoomMessageEvent, client: Client| {
+                let tx = tx.clone();
+                async move {
+                    let user_id = match client.user_id().await {
+                        Some(user_id) => user_id,
+                        None => return,
+                    };
+                    if let MessageType::VerificationRequest(content) = &ev.content.msgtype {
+                        if content.to != user_id {
+                            return;
+                        }
+                        if let Some(request) = client
+                            .encryption()
+                            .get_verification_request(&ev.sender, ev.event_id.as_str())
+                            .await
+                        {
+                            if (tx.send(VerificationEvent::Request(request)).await).is_ok() {};
+                        }
+                    }
+                }
+            }
+        })
+        .await;
+
+    // SAS started by another device of the own user
+    client
+        .register_event_handler({
+            let tx = tx.clone();
+            move |ev: ToDeviceKeyVerificationStartEvent, client: Client| {
+                let tx = tx.clone();
+                async move {
+                    if let Some(Verification::SasV1(sas)) = client
+                        .encryption()
+                        .get_verification(&ev.sender, ev.content.transaction_id.as_str())
+                        .await
+                    {
+                        if (tx.send(VerificationEvent::Sas(sas)).await).is_ok() {};
+                    }
+                }
+            }
+        })
+        .await;
+
+    // SAS started in a room, the own start event is ignored
+    client
+        .register_event_handler({
+            let tx = tx.clone();
+            move |ev: OriginalSyncKeyVerificationStartEvent, client: Client| {
+                let tx = tx.clone();
+                async move {
+                    if client.user_id().await.as_deref() == Some(&*ev.sender) {
+                        return;
+                    }
+                    if let Some(Verification::SasV1(sas)) = client
+                        .encryption()
+                        .get_verification(&ev.sender, ev.content.relates_to.event_id.as_str())
+                        .await
+                    {
+                        if (tx.send(VerificationEvent::Sas(sas)).await).is_ok() {};
+                    }
+                }
+            }
+        })
+        .await;
+}
+
+/// Returns the directory in which the data of an account is stored
+/// The directory is created if it does not exist.
+/// # Arguments
+/// * `home_server` - The homeserver url
+/// * `username` - The username
+/// # Returns
+/// * `PathBuf` - $XDG_DATA_HOME/matrix_client/<username>@<homeserver>
+pub fn account_dir(home_server: &Url, username: &str) -> Result<PathBuf, Error> {
+    let data_dir = match dirs::data_dir() {
+        Some(dir) => dir,
+        None => {
+            return Err(Error::Io(io::Error::new(
+                io::ErrorKind::NotFound,
+                "Could not find data directory",
+            )));
+        }
+    };
+    let account = format!(
+        "{}@{}",
+        username.trim_start_matches('@'),
+        home_server.host_str().unwrap_or("unknown")
+    )
+    .replace(
+        |c: char| !c.is_alphanumeric() && c != '@' && c != '.' && c != '-',
+        "_",
+    );
+
+    let dir = data_dir.join("matrix_client").join(account);
+    fs::create_dir_all(&dir)?;
+
+    // The session, the store and the backup state must not be readable by other users
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(&dir, fs::Permissions::from_mode(0o700))?;
+    }
+    Ok(dir)
+}
+
+/// Checks if a session is saved for an account which can still be used.
+/// A session whose access token is not known to the homeserver anymore is deleted,
+/// so the password is asked for again. It is kept if the homeserver can not be reached.
+/// # Arguments
+/// * `home_server` - The homeserver url
+/// * `username` - The username
+pub async fn has_session(home_server: &Url, username: &str) -> Result<bool, Error> {
+    let session = match load_session(&session_path(home_server, username)?)? {
+        Some(session) => session,
+        None => return Ok(false),
+    };
+
+    let client = match Client::new(home_server.clone()).await {
+        Ok(client) => client,
+        Err(err) => {
+            return Err(Error::Http(err));
+        }
+    };
+    client.restore_login(session).await?;
+    match client.send(whoami::v3::Request::new(), None).await {
+        Err(err) if is_unknown_token(&err) => {
+            remove_session(home_server, username)?;
+            Ok(false)
+        }
+        _ => Ok(true),
+    }
+}
+
+/// Returns true if the homeserver rejected a request because of an unknown access token
+/// # Arguments
+/// * `err` - The error of the request
+fn is_unknown_token(err: &HttpError) -> bool {
+    match err {
+        HttpError::Api(FromHttpResponseError::Server(ServerError::Known(
+            RumaApiError::ClientApi(err),
+        ))) => matches!(err.kind, ErrorKind::UnknownToken { .. }),
+        _ => false,
+    }
+}
+
+/// Delete the saved session of an account
+/// The store and the key backup state belong to the device of the session and are deleted too.
+/// # Arguments
+/// * `home_server` - The homeserver url
+/// * `username` - The username
+fn remove_session(home_server: &Url, username: &str) -> Result<(), Error> {
+    let session_file = session_path(home_server, username)?;
+    if session_file.exists() {
+        fs::remove_file(&session_file)?;
+    }
+
+    // The store belongs to the logged out device and can not be reused
+    let store = store_path(home_server, username)?;
+    if store.exists() {
+        fs::remove_dir_all(&store)?;
+    }
+    // The uploaded room keys of the backup were the keys of the store
+    let backup_file = backup_path(home_server, username)?;
+    if backup_file.exists() {
+        fs::remove_file(&backup_file)?;
+    }
+    Ok(())
+}
+
+/// Returns the path of the session file of an account
+/// # Arguments
+/// * `home_server` - The homeserver url
+/// * `username` - The username
+fn session_path(home_server: &Url, username: &str) -> Result<PathBuf, Error> {
+    Ok(account_dir(home_server, username)?.join("session.json"))
+}
+
+/// Returns the path of the state store of an account
+/// # Arguments
+/// * `home_server` - The homeserver url
+/// * `username` - The username
+fn store_path(home_server: &Url, username: &str) -> Result<PathBuf, Error> {
+    Ok(account_dir(home_server, username)?.join("store"))
+}
+
+/// Load a saved session
+/// # Arguments
+/// * `path` - The path of the session file
+/// # Returns
+/// * `Option<Session>` - The session or None if no session is saved
+fn load_session(path: &Path) -> Result<Option<Session>, Error> {
+    if !path.exists() {
+        return Ok(None);
+    }
+    let session = fs::read_to_string(path)?;
+    Ok(Some(serde_json::from_str(&session)?))
+}
+
+/// Save a session, so it can be restored on the next start
+/// # Arguments
+/// * `path` - The path of the session file
+/// * `session` - The session to save
+fn save_session(path: &Path, session: &Session) -> Result<(), Error> {
+    let session = serde_json::to_string(session)?;
+
+    // The access token must not be readable by other users
+    create_private_file(path)?.write_all(session.as_bytes())?;
+    Ok(())
+}
+
+/// Create or truncate a file only the user can read and write.
+/// The permissions are set before anything is written to it.
+/// # Arguments
+/// * `path` - The path of the file
+fn create_private_file(path: &Path) -> Result<fs::File, Error> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let file = options.open(path)?;
+
+    // The mode only applies to new files, an existing file may still be readable by others
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(fs::Permissions::from_mode(0o600))?;
+    }
+    Ok(file)
+}
+
+/// Returns the path of the file the state of the key backup of an account is saved in
+/// # Arguments
+/// * `home_server` - The homeserver url
+/// * `username` - The username
+pub fn backup_path(home_server: &Url, username: &str) -> Result<PathBuf, Error> {
+    Ok(account_dir(home_server, username)?.join("backup.json"))
+}
+
+/// Load the state of the key backup
+/// # Arguments
+/// * `path` - The path of the backup file
+/// # Returns
+/// * `Option<BackupState>` - The state or None if the backup is not enabled
+fn load_backup_state(path: &Path) -> Result<Option<BackupState>, Error> {
+    if !path.exists() {
+        return Ok(None);
+    }
+    let state = fs::read_to_string(path)?;
+    Ok(Some(serde_json::from_str(&state)?))
+}
+
+/// Save the state of the key backup
+/// # Arguments
+/// * `path` - The path of the backup file
+/// * `state` - The state to save
+fn save_backup_state(path: &Path, state: &BackupState) -> Result<(), Error> {
+    let state = serde_json::to_string(state)?;
+    create_private_file(path)?.write_all(state.as_bytes())?;
+    Ok(())
+}
+
+/// A temporary key export, deleted when it is dropped,
+/// so it is also removed if the import or export fails
+struct TemporaryKeyFile {
+    path: PathBuf,
+}
+
+impl TemporaryKeyFile {
+    /// Create an empty key file only the user can read, next to the backup file
+    /// # Arguments
+    /// * `backup_file` - The file the state of the backup is saved in
+    fn create(backup_file: &Path) -> Result<TemporaryKeyFile, Error> {
+        let path = backup_file.with_file_name(format!("keys-{}.tmp", random_salt()));
+        create_private_file(&path)?;
+        Ok(TemporaryKeyFile { path })
+    }
+}
+
+impl Drop for TemporaryKeyFile {
+    fn drop(&mut self) {
+        if (fs::remove_file(&self.path)).is_ok() {};
+    }
 }
 
 #[async_trait]
@@ -200,6 +1787,9 @@ impl InvitedExt for Invited {
 pub fn convert_message_type(msgtype: MessageType, homeserver_url: Url) -> String {
     match msgtype {
         MessageType::Text(content) => content.body,
+        MessageType::Emote(content) => content.body,
+        MessageType::Notice(content) => content.body,
+        MessageType::ServerNotice(content) => content.body,
         MessageType::Audio(content) => {
             "Has send audio: ".to_string()
                 + &content.body
@@ -238,7 +1828,7 @@ pub fn convert_message_type(msgtype: MessageType, homeserver_url: Url) -> String
 fn handle_media_source(source: MediaSource, homeserver_url: Url) -> String {
     match source {
         MediaSource::Plain(mxc) => convert_mxc_to_url(mxc, homeserver_url).to_string(),
-        MediaSource::Encrypted(_) => "File is encrypted. Not Implemented!".to_string(),
+        MediaSource::Encrypted(_) => "(encrypted, download to view)".to_string(),
     }
 }
 
@@ -260,3 +1850,248 @@ fn convert_mxc_to_url(mxc: OwnedMxcUri, mut base_url: Url) -> Url {
         Err(_) => base_url,
     }
 }
+
+/// Create a rich reply to a message
+/// The original message is quoted in the plain and html body for clients without reply support.
+/// # Arguments
+/// * `content` - The text or emote message which replies
+/// * `room_id` - The room of the original message
+/// * `event_id` - The event id of the original message
+/// * `sender` - The user id of the sender of the original message
+/// * `original_body` - The body of the original message, without its own reply fallback
+/// # Returns
+/// * `RoomMessageEventContent` - The reply with the in_reply_to relation
+pub fn reply_content(
+    content: RoomMessageEventContent,
+    room_id: &str,
+    event_id: &EventId,
+    sender: &str,
+    original_body: &str,
+) -> RoomMessageEventContent {
+    let mut quote = original_body
+        .lines()
+        .map(|line| format!("> {}", line))
+        .collect::<Vec<String>>();
+    match quote.first_mut() {
+        Some(first) => *first = format!("> <{}> {}", sender, &first[2..]),
+        None => quote.push(format!("> <{}>", sender)),
+    }
+    let (message, message_html) = match text_bodies(&content) {
+        Some((body, Some(html))) => (body, html),
+        Some((body, None)) => {
+            let html = escape_html(&body).replace('\n', "<br>");
+            (body, html)
+        }
+        None => (String::new(), String::new()),
+    };
+    let body = format!("{}\n\n{}", quote.join("\n"), message);
+
+    let html_body = format!(
+        "<mx-reply><blockquote><a href=\"https://matrix.to/#/{room}/{event}\">In reply to</a> \
+         <a href=\"https://matrix.to/#/{sender}\">{sender}</a><br>{original}</blockquote></mx-reply>{message}",
+        room = room_id,
+        event = event_id,
+        sender = sender,
+        original = escape_html(original_body).replace('\n', "<br>"),
+        message = message_html,
+    );
+
+    let msgtype = match content.msgtype {
+        MessageType::Emote(_) => {
+            MessageType::Emote(EmoteMessageEventContent::html(body, html_body))
+        }
+        _ => MessageType::Text(TextMessageEventContent::html(body, html_body)),
+    };
+    let mut content = RoomMessageEventContent::new(msgtype);
+    content.relates_to = Some(Relation::Reply {
+        in_reply_to: InReplyTo::new(event_id.to_owned()),
+    });
+    content
+}
+
+/// Create a text message
+/// # Arguments
+/// * `message` - The text of the message
+/// * `markdown` - Whether the text is formatted as markdown
+/// # Returns
+/// * `RoomMessageEventContent` - The message, with an html body if the markdown has formatting
+pub fn text_content(message: &str, markdown: bool) -> RoomMessageEventContent {
+    match markdown {
+        true => RoomMessageEventContent::text_markdown(message),
+        false => RoomMessageEventContent::text_plain(message),
+    }
+}
+
+/// Create an emote, shown like "* alice waves"
+/// # Arguments
+/// * `message` - The text of the emote, without the name of the sender
+/// * `markdown` - Whether the text is formatted as markdown
+/// # Returns
+/// * `RoomMessageEventContent` - The emote, with an html body if the markdown has formatting
+pub fn emote_content(message: &str, markdown: bool) -> RoomMessageEventContent {
+    let emote = match markdown {
+        true => EmoteMessageEventContent::markdown(message),
+        false => EmoteMessageEventContent::plain(message),
+    };
+    RoomMessageEventContent::new(MessageType::Emote(emote))
+}
+
+/// Returns the plain body and the html body of a text or emote message
+/// # Arguments
+/// * `content` - The message
+fn text_bodies(content: &RoomMessageEventContent) -> Option<(String, Option<String>)> {
+    let (body, formatted) = match &content.msgtype {
+        MessageType::Text(text) => (&text.body, &text.formatted),
+        MessageType::Emote(emote) => (&emote.body, &emote.formatted),
+        _ => return None,
+    };
+    Some((
+        body.to_string(),
+        formatted.as_ref().map(|f| f.body.to_string()),
+    ))
+}
+
+/// Create an edit of a message
+/// # Arguments
+/// * `message` - The new text of the message
+/// * `markdown` - Whether the new text is formatted as markdown
+/// * `event_id` - The event id of the edited message
+/// # Returns
+/// * `RoomMessageEventContent` - The edit with the m.replace relation
+pub fn edit_content(message: &str, markdown: bool, event_id: &EventId) -> RoomMessageEventContent {
+    let new_content = text_content(message, markdown);
+    // Clients without edit support show the body with a leading "*"
+    let mut content = match text_bodies(&new_content).and_then(|(_, html)| html) {
+        Some(html) => {
+            RoomMessageEventContent::text_html(format!("* {}", message), format!("* {}", html))
+        }
+        None => RoomMessageEventContent::text_plain(format!("* {}", message)),
+    };
+    content.relates_to = Some(Relation::Replacement(Replacement::new(
+        event_id.to_owned(),
+        Box::new(new_content),
+    )));
+    content
+}
+
+/// Move a message into a thread
+/// # Arguments
+/// * `content` - The message
+/// * `root` - The event id of the thread root
+/// * `latest` - The event id of the latest message in the thread, shown as reply target
+///   by clients without thread support
+/// # Returns
+/// * `RoomMessageEventContent` - The message with the m.thread relation
+pub fn thread_content(
+    mut content: RoomMessageEventContent,
+    root: &EventId,
+    latest: &EventId,
+) -> RoomMessageEventContent {
+    content.relates_to = Some(Relation::Thread(Thread::plain(
+        root.to_owned(),
+        latest.to_owned(),
+    )));
+    content
+}
+
+/// Removes the quoted original message from the body of a reply
+/// # Arguments
+/// * `body` - The body of the reply
+pub fn strip_reply_fallback(body: &str) -> String {
+    if !body.starts_with("> ") {
+        return body.to_string();
+    }
+    body.lines()
+        .skip_while(|line| line.starts_with('>'))
+        .skip_while(|line| line.is_empty())
+        .collect::<Vec<&str>>()
+        .join("\n")
+}
+
+/// Escapes the characters with a special meaning in html
+/// # Arguments
+/// * `text` - The text to escape
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Open a file with the system handler or a configured command
+/// # Arguments
+/// * `path` - The file to open
+/// * `command` - The command to open the file with, None for the system handler
+pub fn open_file(path: &Path, command: &Option<String>) -> io::Result<()> {
+    let command = match command {
+        Some(command) => command.as_str(),
+        None if cfg!(target_os = "macos") => "open",
+        None => "xdg-open",
+    };
+    // Output of the handler would break the terminal UI
+    process::Command::new(command)
+        .arg(path)
+        .stdin(process::Stdio::null())
+        .stdout(process::Stdio::null())
+        .stderr(process::Stdio::null())
+        .spawn()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reply_fallback_is_stripped() {
+        assert_eq!(
+            strip_reply_fallback("> <@alice:example.org> Hello\n> second line\n\nHi Alice"),
+            "Hi Alice"
+        );
+        assert_eq!(
+            strip_reply_fallback("> <@alice:example.org> Hello\n\nfirst\n\nsecond"),
+            "first\n\nsecond"
+        );
+    }
+
+    #[test]
+    fn quotes_after_the_fallback_are_kept() {
+        assert_eq!(
+            strip_reply_fallback("> <@alice:example.org> Hello\n\n> quoted\nanswer"),
+            "> quoted\nanswer"
+        );
+    }
+
+    #[test]
+    fn bodies_without_fallback_are_unchanged() {
+        assert_eq!(
+            strip_reply_fallback("Hello\n> not a fallback"),
+            "Hello\n> not a fallback"
+        );
+        assert_eq!(strip_reply_fallback(">no space"), ">no space");
+        assert_eq!(strip_reply_fallback(""), "");
+    }
+
+    #[test]
+    fn upload_progress_is_reported_in_whole_percents() {
+        let (tx, mut rx) = mpsc::channel(100);
+        let data = vec![0; 1000];
+        let mut reader = ProgressReader::new(&data[..], "file.txt", 1000, tx);
+        let mut buffer = [0; 300];
+        while reader.read(&mut buffer).unwrap() > 0 {}
+
+        let mut reports = Vec::new();
+        while let Ok(report) = rx.try_recv() {
+            reports.push(report);
+        }
+        assert_eq!(
+            reports,
+            vec![
+                "Uploading file.txt... 30%",
+                "Uploading file.txt... 60%",
+                "Uploading file.txt... 90%",
+                "Uploading file.txt... 100%",
+            ]
+        );
+    }
+}
@@ -1,113 +1,231 @@
-use crate::app::{App, Room, Tabs};
+use crate::app::{
+    App, HistoryState, InputTarget, Room, SendState, Tabs, TimelineContent, TimelineItem,
+    VerificationState,
+};
+use crate::config::KeyBindings;
 use crate::matrix::*;
+use crate::{highlight, html};
+use matrix_sdk::ruma::events::room::message::MessageType;
 
-use crossterm::event::{self, poll, Event, KeyCode};
-use std::{io, time::Duration};
-use tokio::sync::mpsc::Receiver;
+use crossterm::{
+    event::{self, poll, Event, KeyCode},
+    execute,
+    terminal::SetTitle,
+};
+use std::{collections::HashSet, io, time::Duration};
 
 use tui::{
     backend::Backend,
     layout::{Alignment, Constraint, Direction, Layout, Rect},
     style::{Color, Modifier, Style},
     text::{Span, Spans, Text},
-    widgets::{Block, BorderType, Borders, List, ListItem, Paragraph, Wrap},
+    widgets::{Block, BorderType, Borders, Clear, List, ListItem, Paragraph, Wrap},
     Frame, Terminal,
 };
 
-use matrix_sdk::{
-    room::Room as MatrixRoom,
-    ruma::events::room::{
-        member::OriginalSyncRoomMemberEvent, message::OriginalSyncRoomMessageEvent,
-    },
-    Client,
-};
-
 use unicode_width::UnicodeWidthStr;
 
+/// Title of the terminal, prefixed with the number of unread notifications
+const TITLE: &str = "matrix_client";
+
+/// Number of readers shown next to a message, further readers are counted
+const MAX_READERS: usize = 5;
+
+/// Commands with secret arguments and the number of arguments which are shown.
+/// All following arguments are hidden in the input widget.
+const SECRET_COMMANDS: [(&str, usize); 5] = [
+    ("/cross-signing ", 0),
+    ("/keys export ", 1),
+    ("/keys import ", 1),
+    ("/backup enable ", 0),
+    ("/backup restore ", 0),
+];
+
 /// The main UI loop.
 /// This function loops until the user quits the application.
 /// # Arguments
 ///  * `termial` - The terminal to use
 /// * `app` - The application to use
-/// * `rx` - The channel to receive events from
+/// * `receivers` - The channels to receive events from
 /// # Returns
 /// * `Result<(), io::Error>` - The result of the operation
 pub async fn run_ui<B: Backend>(
     terminal: &mut Terminal<B>,
     mut app: App,
-    mut rx_messages: Receiver<(OriginalSyncRoomMessageEvent, MatrixRoom, Client)>,
-    mut rx_rooms: Receiver<(OriginalSyncRoomMemberEvent, MatrixRoom, Client)>,
+    mut receivers: EventReceivers,
 ) -> io::Result<()> {
+    // The number of notifications shown in the terminal title
+    let mut title_notifications = None;
     loop {
         // Check rx
-        if let Ok((ev, room, client)) = rx_messages.try_recv() {
+        if let Ok((ev, room, client)) = receivers.messages.try_recv() {
             app.handle_matrix_message_event(ev, room, client).await;
         }
-        if let Ok((ev, room, client)) = rx_rooms.try_recv() {
+        if let Ok((ev, room, _)) = receivers.encrypted.try_recv() {
+            app.handle_matrix_encrypted_event(ev, room);
+        }
+        if let Ok((ev, room, client)) = receivers.rooms.try_recv() {
             app.handle_matrix_room_event(ev, room, client).await;
         }
+        if let Ok((ev, room, _)) = receivers.state.try_recv() {
+            app.handle_matrix_state_event(ev, room);
+        }
+        if let Ok((ev, room, _)) = receivers.redactions.try_recv() {
+            app.handle_matrix_redaction_event(ev, room);
+        }
+        if let Ok((ev, room, _)) = receivers.reactions.try_recv() {
+            app.handle_matrix_reaction_event(ev, room);
+        }
+        if let Ok((ev, room, _)) = receivers.typing.try_recv() {
+            app.handle_matrix_typing_event(ev, room);
+        }
+        if let Ok((ev, room, _)) = receivers.receipts.try_recv() {
+            app.handle_matrix_receipt_event(ev, room);
+        }
+        if let Ok((ev, room, _)) = receivers.read_markers.try_recv() {
+            app.handle_matrix_read_marker_event(ev, room);
+        }
+        if let Ok((room_id, notifications, highlights)) = receivers.unread.try_recv() {
+            app.handle_unread_counts(room_id, notifications, highlights);
+        }
+        if let Ok(ev) = receivers.verification.try_recv() {
+            app.handle_verification_event(ev);
+        }
+        if let Ok(status) = receivers.status.try_recv() {
+            app.status = Some(status);
+        }
+        if let Ok(status) = app.rx_status.try_recv() {
+            app.status = Some(status);
+        }
+        if let Ok((room_id, transaction_id, event_id)) = app.rx_sent.try_recv() {
+            app.handle_sent_message(room_id, transaction_id, event_id);
+        }
+        if let Ok((room_id, original, body)) = app.rx_failed_edits.try_recv() {
+            app.handle_failed_edit(room_id, original, body);
+        }
+        if let Ok((room_id, events, oldest_event, history)) = app.rx_history.try_recv() {
+            app.handle_history(room_id, events, oldest_event, history)
+                .await;
+        }
+        if let Ok((room_id, receipts)) = app.rx_loaded_receipts.try_recv() {
+            app.handle_loaded_receipts(room_id, receipts);
+        }
+        if let Ok((room_id, events)) = app.rx_decrypted.try_recv() {
+            app.handle_decrypted(room_id, events).await;
+        }
+        app.update_verification().await;
+        app.load_receipts();
+        app.mark_current_room_read();
+
+        let notifications = app.total_notifications();
+        if title_notifications != Some(notifications) {
+            let title = match notifications {
+                0 => TITLE.to_string(),
+                n => format!("({}) {}", n, TITLE),
+            };
+            execute!(io::stdout(), SetTitle(title))?;
+            title_notifications = Some(notifications);
+        }
 
         terminal.draw(|f| ui(f, &mut app))?;
 
         if poll(Duration::from_millis(10))? {
             if let Event::Key(key) = event::read()? {
+                let keys = app.config.keybindings.clone();
+
+                // The verification modal takes all keys while it is open
+                if app.verification.is_some() {
+                    match key.code {
+                        code if code == keys.confirm.0 => {
+                            app.confirm_verification().await;
+                        }
+                        code if code == keys.cancel.0 || code == keys.quit.0 => {
+                            app.cancel_verification().await;
+                        }
+                        _ => {}
+                    }
+                    continue;
+                }
+
                 match app.current_tab {
                     // Control in room tab
                     Tabs::Room => match key.code {
-                        KeyCode::Esc => {
+                        code if code == keys.quit.0 => {
                             return Ok(());
                         }
-                        KeyCode::Up => {
+                        code if code == keys.up.0 => {
                             app.rooms.previous_room();
+                            app.cancel_input_target();
+                            app.close_thread();
                         }
-                        KeyCode::Down => {
+                        code if code == keys.down.0 => {
                             app.rooms.next_room();
+                            app.cancel_input_target();
+                            app.close_thread();
                         }
-                        KeyCode::Tab => {
+                        code if code == keys.next_tab.0 => {
                             app.next_tab();
                         }
                         _ => {}
                     },
                     // Control in message tab
                     Tabs::Messages => match key.code {
-                        KeyCode::Esc => {
+                        code if code == keys.quit.0 => {
                             return Ok(());
                         }
-                        KeyCode::Up => match app.rooms.get_current_room() {
-                            Some(room) => {
-                                room.messages.previous_message();
-                            }
-                            None => {}
-                        },
-                        KeyCode::Down => match app.rooms.get_current_room() {
+                        code if code == keys.up.0 => {
+                            app.previous_message();
+                        }
+                        code if code == keys.down.0 => match app.rooms.get_current_room() {
                             Some(room) => {
                                 room.messages.next_message();
                             }
                             None => {}
                         },
-                        KeyCode::Tab => {
+                        code if code == keys.download.0 => {
+                            app.download_selected_attachment(false);
+                        }
+                        code if code == keys.open.0 => {
+                            app.download_selected_attachment(true);
+                        }
+                        code if code == keys.reply.0 => {
+                            app.start_reply();
+                        }
+                        code if code == keys.edit.0 => {
+                            app.start_edit();
+                        }
+                        code if code == keys.redact.0 => {
+                            app.start_redaction();
+                        }
+                        code if code == keys.react.0 => {
+                            app.start_reaction();
+                        }
+                        code if code == keys.thread.0 => {
+                            app.open_thread();
+                        }
+                        code if code == keys.next_tab.0 => {
                             app.next_tab();
                         }
                         _ => {}
                     },
                     // Control in members tab
                     Tabs::Members => match key.code {
-                        KeyCode::Esc => {
+                        code if code == keys.quit.0 => {
                             return Ok(());
                         }
-                        KeyCode::Up => match app.rooms.get_current_room() {
+                        code if code == keys.up.0 => match app.rooms.get_current_room() {
                             Some(room) => {
                                 room.members.previous_member();
                             }
                             None => {}
                         },
-                        KeyCode::Down => match app.rooms.get_current_room() {
+                        code if code == keys.down.0 => match app.rooms.get_current_room() {
                             Some(room) => {
                                 room.members.next_member();
                             }
                             None => {}
                         },
-                        KeyCode::Char('k') => match app.rooms.get_current_room() {
+                        code if code == keys.kick.0 => match app.rooms.get_current_room() {
                             Some(room) => {
                                 match room.members.state.selected() {
                                     Some(i) => {
@@ -120,34 +238,96 @@ pub async fn run_ui<B: Backend>(
                             }
                             None => {}
                         },
-                        KeyCode::Tab => {
+                        code if code == keys.verify.0 => {
+                            let user_id = match app.rooms.get_current_room() {
+                                Some(room) => room
+                                    .members
+                                    .state
+                                    .selected()
+                                    .map(|i| room.members.members[i].1.to_string()),
+                                None => None,
+                            };
+                            if let Some(user_id) = user_id {
+                                app.start_verification(user_id).await;
+                            }
+                        }
+                        code if code == keys.next_tab.0 => {
                             app.next_tab();
                         }
                         _ => {}
                     },
                     // Control in input tab
-                    Tabs::Input => match key.code {
-                        KeyCode::Esc => {
-                            return Ok(());
+                    // Characters are always typed, even if they are bound to a key
+                    Tabs::Input => {
+                        match key.code {
+                            KeyCode::Char(c) => {
+                                app.input.push(c);
+                            }
+                            // Cancel a reply, edit or deletion before quitting
+                            code if code == keys.quit.0 && app.input_target.is_some() => {
+                                app.cancel_input_target();
+                            }
+                            code if code == keys.quit.0 => {
+                                return Ok(());
+                            }
+                            // The path of an upload is completed instead of switching tabs
+                            code if code == keys.next_tab.0 => {
+                                if !app.complete_upload_path() {
+                                    app.next_tab();
+                                }
+                            }
+                            code if code == keys.send.0 => {
+                                app.submit_input().await;
+                            }
+                            KeyCode::Backspace => {
+                                app.input.pop();
+                            }
+                            _ => {}
                         }
-                        KeyCode::Tab => {
-                            app.next_tab();
+                        app.update_typing_notice();
+                    }
+                    // Control in the messages of the thread pane
+                    Tabs::Thread => match key.code {
+                        code if code == keys.quit.0 || code == keys.thread.0 => {
+                            app.close_thread();
                         }
-                        KeyCode::Enter => match app.rooms.get_current_room() {
-                            Some(room) => {
-                                let message: String = app.input.drain(..).collect();
-                                app.client.send_message(&room.id, &message).await;
+                        code if code == keys.up.0 => {
+                            if let Some(thread) = app.current_thread() {
+                                thread.previous_message();
+                            }
+                        }
+                        code if code == keys.down.0 => {
+                            if let Some(thread) = app.current_thread() {
+                                thread.next_message();
                             }
-                            None => {}
-                        },
-                        KeyCode::Char(c) => {
-                            app.input.push(c);
                         }
-                        KeyCode::Backspace => {
-                            app.input.pop();
+                        code if code == keys.next_tab.0 => {
+                            app.next_tab();
                         }
                         _ => {}
                     },
+                    // Control in the input of the thread pane
+                    Tabs::ThreadInput => {
+                        match key.code {
+                            KeyCode::Char(c) => {
+                                app.thread_input.push(c);
+                            }
+                            code if code == keys.quit.0 => {
+                                app.close_thread();
+                            }
+                            code if code == keys.next_tab.0 => {
+                                app.next_tab();
+                            }
+                            code if code == keys.send.0 => {
+                                app.submit_thread_input();
+                            }
+                            KeyCode::Backspace => {
+                                app.thread_input.pop();
+                            }
+                            _ => {}
+                        }
+                        app.update_typing_notice();
+                    }
                 }
             }
         }
@@ -160,10 +340,17 @@ pub async fn run_ui<B: Backend>(
 /// * `f` - The frame to draw on.
 /// * `app` - The application state.
 fn ui<B: Backend>(f: &mut Frame<B>, app: &mut App) {
+    let room_list_width = app.config.ui.room_list_width;
     let chunks = Layout::default()
         .direction(Direction::Horizontal)
         .margin(1)
-        .constraints([Constraint::Percentage(15), Constraint::Percentage(85)].as_ref())
+        .constraints(
+            [
+                Constraint::Percentage(room_list_width),
+                Constraint::Percentage(100 - room_list_width),
+            ]
+            .as_ref(),
+        )
         .split(f.size());
 
     //Room Select Widget
@@ -172,25 +359,256 @@ fn ui<B: Backend>(f: &mut Frame<B>, app: &mut App) {
     // Message Widget
     match app.rooms.get_current_room() {
         Some(room) => {
+            // The reply, edit or deletion banner is shown between the messages and the input
+            let banner = match &app.input_target {
+                Some(InputTarget::Reply(message)) => Some(("Replying to", message, None)),
+                Some(InputTarget::Edit(message)) => Some(("Editing", message, None)),
+                Some(InputTarget::Redact(message)) => Some((
+                    "Delete message of",
+                    message,
+                    Some(format!(
+                        "  {} deletes, the input is the optional reason, {} cancels",
+                        app.config.keybindings.send, app.config.keybindings.quit
+                    )),
+                )),
+                Some(InputTarget::React(message)) => Some((
+                    "React to",
+                    message,
+                    Some(format!(
+                        "  type an emoji or :shortcode:, {} reacts or removes your reaction",
+                        app.config.keybindings.send
+                    )),
+                )),
+                None => None,
+            };
+            let banner_height = match banner {
+                Some(_) => 1,
+                None => 0,
+            };
             let chunks = Layout::default()
                 .direction(Direction::Vertical)
-                .constraints([Constraint::Min(5), Constraint::Length(3)].as_ref())
+                .constraints(
+                    [
+                        Constraint::Min(5),
+                        Constraint::Length(banner_height),
+                        Constraint::Length(3),
+                    ]
+                    .as_ref(),
+                )
                 .split(chunks[1]);
-            draw_message_tab(f, &app.current_tab, room, chunks[0]);
-            draw_input_tab(f, app, chunks[1]);
+            // The thread pane is shown next to the messages
+            let message_chunks = match app.thread {
+                Some(_) => Layout::default()
+                    .direction(Direction::Horizontal)
+                    .constraints([Constraint::Percentage(50), Constraint::Percentage(50)].as_ref())
+                    .split(chunks[0]),
+                None => vec![chunks[0]],
+            };
+            draw_message_tab(
+                f,
+                &app.current_tab,
+                &app.config.ui.timestamp_format,
+                &app.user_id,
+                room,
+                message_chunks[0],
+            );
+            if let Some(root) = &app.thread {
+                let thread_chunks = Layout::default()
+                    .direction(Direction::Vertical)
+                    .constraints([Constraint::Min(3), Constraint::Length(3)].as_ref())
+                    .split(message_chunks[1]);
+                draw_thread_pane(
+                    f,
+                    &app.current_tab,
+                    &app.config.ui.timestamp_format,
+                    &app.user_id,
+                    room,
+                    root,
+                    thread_chunks[0],
+                );
+                draw_thread_input(f, &app.current_tab, &app.thread_input, thread_chunks[1]);
+            }
+            if let Some((label, message, hint)) = banner {
+                draw_input_banner(f, label, message, hint, chunks[1]);
+            }
+            draw_input_tab(f, app, chunks[2]);
         }
         None => {
-            draw_welcome_tab(f, &app.current_tab, chunks[1]);
+            draw_welcome_tab(f, &app.current_tab, &app.config.keybindings, chunks[1]);
         }
     };
+
+    // Verification modal
+    if let Some(verification) = &app.verification {
+        draw_verification_modal(f, &app.config.keybindings, verification, f.size());
+    }
+}
+
+/// Returns a rectangle centered in the given area
+/// # Arguments
+/// * `percent_x` - The width in percent of the area.
+/// * `percent_y` - The height in percent of the area.
+/// * `area` - The area to center in.
+fn centered_rect(percent_x: u16, percent_y: u16, area: Rect) -> Rect {
+    let vertical = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints(
+            [
+                Constraint::Percentage((100 - percent_y) / 2),
+                Constraint::Percentage(percent_y),
+                Constraint::Percentage((100 - percent_y) / 2),
+            ]
+            .as_ref(),
+        )
+        .split(area);
+    Layout::default()
+        .direction(Direction::Horizontal)
+        .constraints(
+            [
+                Constraint::Percentage((100 - percent_x) / 2),
+                Constraint::Percentage(percent_x),
+                Constraint::Percentage((100 - percent_x) / 2),
+            ]
+            .as_ref(),
+        )
+        .split(vertical[1])[1]
+}
+
+/// Draws the verification modal
+/// Shows the state of the verification and the emojis to compare.
+/// # Arguments
+/// * `f` - The frame to draw on.
+/// * `keys` - The configured keybindings.
+/// * `verification` - The running verification.
+/// * `area` - The area to center the modal in.
+fn draw_verification_modal<B>(
+    f: &mut Frame<B>,
+    keys: &KeyBindings,
+    verification: &VerificationState,
+    area: Rect,
+) where
+    B: Backend,
+{
+    let user = &verification.user_id;
+    let mut text = vec![Spans::from("")];
+
+    if let Some(error) = &verification.error {
+        text.push(Spans::from(Span::styled(
+            error.to_string(),
+            Style::default().fg(Color::Red),
+        )));
+        text.push(Spans::from(""));
+        text.push(Spans::from(format!("{}: close", keys.confirm)));
+    } else if let Some(sas) = &verification.sas {
+        if sas.is_done() {
+            text.push(Spans::from(Span::styled(
+                format!("{} is verified", user),
+                Style::default().fg(Color::Green),
+            )));
+            text.push(Spans::from(""));
+            text.push(Spans::from(format!("{}: close", keys.confirm)));
+        } else if sas.is_cancelled() {
+            text.push(Spans::from(Span::styled(
+                "Verification cancelled",
+                Style::default().fg(Color::Red),
+            )));
+            text.push(Spans::from(""));
+            text.push(Spans::from(format!("{}: close", keys.confirm)));
+        } else if !verification.accepted {
+            text.push(Spans::from(format!(
+                "{} wants to verify by comparing emojis",
+                user
+            )));
+            text.push(Spans::from(""));
+            text.push(Spans::from(format!(
+                "{}: accept   {}: decline",
+                keys.confirm, keys.cancel
+            )));
+        } else if let Some(emojis) = sas.emoji() {
+            text.push(Spans::from(
+                "Confirm the emojis below are shown on the other device in the same order:",
+            ));
+            text.push(Spans::from(""));
+            text.push(Spans::from(
+                emojis
+                    .iter()
+                    .map(|emoji| format!("{} {}", emoji.symbol, emoji.description))
+                    .collect::<Vec<String>>()
+                    .join("   "),
+            ));
+            text.push(Spans::from(""));
+            text.push(Spans::from(format!(
+                "{}: they match   {}: they don't match",
+                keys.confirm, keys.cancel
+            )));
+        } else if let Some((first, second, third)) = sas.decimals() {
+            text.push(Spans::from(
+                "Confirm the numbers below are shown on the other device:",
+            ));
+            text.push(Spans::from(""));
+            text.push(Spans::from(format!("{} {} {}", first, second, third)));
+            text.push(Spans::from(""));
+            text.push(Spans::from(format!(
+                "{}: they match   {}: they don't match",
+                keys.confirm, keys.cancel
+            )));
+        } else {
+            text.push(Spans::from(format!("Waiting for {}...", user)));
+            text.push(Spans::from(""));
+            text.push(Spans::from(format!("{}: cancel", keys.cancel)));
+        }
+    } else if let Some(request) = &verification.request {
+        if request.is_cancelled() {
+            text.push(Spans::from(Span::styled(
+                "Verification cancelled",
+                Style::default().fg(Color::Red),
+            )));
+            text.push(Spans::from(""));
+            text.push(Spans::from(format!("{}: close", keys.confirm)));
+        } else if !verification.accepted {
+            text.push(Spans::from(format!("{} wants to verify", user)));
+            text.push(Spans::from(""));
+            text.push(Spans::from(format!(
+                "{}: accept   {}: decline",
+                keys.confirm, keys.cancel
+            )));
+        } else if verification.we_started && !request.is_ready() {
+            text.push(Spans::from(format!(
+                "Waiting for {} to accept the request...",
+                user
+            )));
+            text.push(Spans::from(""));
+            text.push(Spans::from(format!("{}: cancel", keys.cancel)));
+        } else {
+            text.push(Spans::from(format!(
+                "Waiting for {} to start the emoji verification...",
+                user
+            )));
+            text.push(Spans::from(""));
+            text.push(Spans::from(format!("{}: cancel", keys.cancel)));
+        }
+    }
+
+    let block = Block::default()
+        .borders(Borders::ALL)
+        .title("Verification")
+        .border_type(BorderType::Thick);
+    let paragraph = Paragraph::new(text)
+        .block(block)
+        .alignment(Alignment::Center)
+        .wrap(Wrap { trim: true });
+    let area = centered_rect(60, 40, area);
+    f.render_widget(Clear, area);
+    f.render_widget(paragraph, area);
 }
 
 /// Draws the welcome widget
 /// # Arguments
 /// * `f` - The frame to draw on.
 /// * `current_tab` - The current tab.
+/// * `keys` - The configured keybindings.
 /// * `area` - The area to draw on.
-fn draw_welcome_tab<B>(f: &mut Frame<B>, current_tab: &Tabs, area: Rect)
+fn draw_welcome_tab<B>(f: &mut Frame<B>, current_tab: &Tabs, keys: &KeyBindings, area: Rect)
 where
     B: Backend,
 {
@@ -199,14 +617,42 @@ where
         Spans::from(" (o o)                 (o o) "),
         Spans::from("(  V  ) Matrix Client (  V  )"),
         Spans::from("--m-m-------------------m-m--"),
-        Spans::from("To switch between tabs use tab key"),
-        Spans::from("To scroll up and down use up and down arrow keys"),
-        Spans::from("To send a message use enter key"),
-        Spans::from("To kick someone in the member list use 'k' key"),
+        Spans::from(format!("To switch between tabs use {} key", keys.next_tab)),
+        Spans::from(format!(
+            "To scroll up and down use {} and {} keys",
+            keys.up, keys.down
+        )),
+        Spans::from(format!("To send a message use {} key", keys.send)),
+        Spans::from(format!(
+            "To kick someone in the member list use {} key",
+            keys.kick
+        )),
+        Spans::from(format!(
+            "To save or open the attachment of a message use {} or {} key",
+            keys.download, keys.open
+        )),
+        Spans::from(format!(
+            "To reply to, edit, delete or react to a message use {}, {}, {} or {} key",
+            keys.reply, keys.edit, keys.redact, keys.react
+        )),
+        Spans::from(format!(
+            "{} in the input cancels replies, edits, deletions and reactions",
+            keys.quit
+        )),
+        Spans::from(format!(
+            "To open the thread of a message use {} key",
+            keys.thread
+        )),
         Spans::from("To leave a room kick yourself"),
-        Spans::from("To quit the client use ESC"),
+        Spans::from(format!(
+            "To verify someone in the member list use {} key",
+            keys.verify
+        )),
+        Spans::from("To set up cross-signing type /cross-signing in the input"),
+        Spans::from("To move room keys use /keys export and /keys import"),
+        Spans::from(format!("To quit the client use {}", keys.quit)),
         Spans::from(""),
-        Spans::from("Note: Room invitations will be accepted automatically"),
+        Spans::from("Room invitations can be accepted automatically, see config.toml"),
     ];
     let block = match current_tab {
         Tabs::Messages => Block::default()
@@ -226,26 +672,59 @@ where
 /// # Arguments
 /// * `f` - The frame to draw on.
 /// * `current_tab` - The current tab.
+/// * `timestamp_format` - The strftime format of message timestamps.
+/// * `user_id` - The user id of the own user, whose reactions are highlighted.
+/// * `room` - The room whose messages are drawn.
 /// * `area` - The area to draw on.
-fn draw_message_tab<B>(f: &mut Frame<B>, current_tab: &Tabs, room: &mut Room, area: Rect)
-where
+fn draw_message_tab<B>(
+    f: &mut Frame<B>,
+    current_tab: &Tabs,
+    timestamp_format: &str,
+    user_id: &str,
+    room: &mut Room,
+    area: Rect,
+) where
     B: Backend,
 {
-    let messages: Vec<ListItem> = room
+    let width = (area.width as usize).saturating_sub(6);
+    render_bodies(&mut room.messages.messages, width);
+    // Shown above the oldest loaded message
+    let marker = match room.history {
+        HistoryState::Idle => None,
+        HistoryState::Loading => Some("── Loading older messages… ──"),
+        HistoryState::Complete => Some("── Beginning of the room ──"),
+        HistoryState::Failed => Some("── Could not load older messages ──"),
+    }
+    .map(|marker| Text::styled(marker, Style::default().fg(Color::DarkGray)));
+    let mut messages: Vec<ListItem> = room
         .messages
         .messages
         .iter()
         .enumerate()
-        .map(|(_i, m)| {
-            let mut text = Text::styled(
-                format!("{}:{}", m.0, m.1),
-                Style::default().fg(Color::Green),
-            );
-            text.extend(Text::raw(textwrap::fill(&m.2, area.width as usize - 6)));
-
-            ListItem::new(text)
+        .map(|(i, m)| {
+            let mut text = message_text(m, room, timestamp_format, user_id, width);
+            if m.event_id.is_some()
+                && m.event_id == room.read_marker
+                && i + 1 < room.messages.messages.len()
+            {
+                text.extend(Text::styled(
+                    "── new messages ──",
+                    Style::default().fg(Color::Red),
+                ));
+            }
+            match (i, &marker) {
+                (0, Some(marker)) => {
+                    let mut marked = marker.clone();
+                    marked.extend(text);
+                    ListItem::new(marked)
+                }
+                _ => ListItem::new(text),
+            }
         })
         .collect();
+    if let (true, Some(marker)) = (messages.is_empty(), marker) {
+        messages.push(ListItem::new(marker));
+    }
 
     let block_message = match current_tab {
         Tabs::Messages => Block::default()
@@ -260,6 +739,317 @@ where
         .highlight_symbol("> ");
 
     f.render_stateful_widget(messages, area, &mut room.messages.state);
+
+    // Shown in the bottom border of the message block
+    if let (Some(typing), true) = (room.typing_text(), area.height > 2) {
+        let footer = Rect {
+            x: area.x + 1,
+            y: area.y + area.height - 1,
+            width: area.width.saturating_sub(2),
+            height: 1,
+        };
+        f.render_widget(
+            Paragraph::new(Span::styled(typing, Style::default().fg(Color::DarkGray))),
+            footer,
+        );
+    }
+}
+
+/// Draws the messages of the thread pane
+/// # Arguments
+/// * `f` - The frame to draw on.
+/// * `current_tab` - The current tab.
+/// * `timestamp_format` - The strftime format of message timestamps.
+/// * `user_id` - The user id of the own user, whose reactions are highlighted.
+/// * `room` - The room of the thread.
+/// * `root` - The event id of the thread root.
+/// * `area` - The area to draw on.
+fn draw_thread_pane<B>(
+    f: &mut Frame<B>,
+    current_tab: &Tabs,
+    timestamp_format: &str,
+    user_id: &str,
+    room: &mut Room,
+    root: &str,
+    area: Rect,
+) where
+    B: Backend,
+{
+    let title = match room.thread_root(root) {
+        Some(m) => format!("Thread | {}: {}", m.sender_name, m.excerpt()),
+        None => "Thread".to_string(),
+    };
+    let width = (area.width as usize).saturating_sub(6);
+    if let Some(thread) = room.threads.get_mut(root) {
+        render_bodies(&mut thread.messages, width);
+    }
+    let messages: Vec<ListItem> = match room.threads.get(root) {
+        Some(thread) => thread
+            .messages
+            .iter()
+            .map(|m| ListItem::new(message_text(m, room, timestamp_format, user_id, width)))
+            .collect(),
+        None => Vec::new(),
+    };
+
+    let block = match current_tab {
+        Tabs::Thread => Block::default()
+            .borders(Borders::ALL)
+            .title(title)
+            .border_type(BorderType::Thick),
+        _ => Block::default().borders(Borders::ALL).title(title),
+    };
+    let messages = List::new(messages)
+        .block(block)
+        .highlight_style(Style::default().add_modifier(Modifier::BOLD))
+        .highlight_symbol("> ");
+
+    match room.threads.get_mut(root) {
+        Some(thread) => f.render_stateful_widget(messages, area, &mut thread.state),
+        None => f.render_widget(messages, area),
+    }
+}
+
+/// Draws the input of the thread pane
+/// # Arguments
+/// * `f` - The frame to draw on.
+/// * `current_tab` - The current tab.
+/// * `input` - The text of the input.
+/// * `area` - The area to draw on.
+fn draw_thread_input<B>(f: &mut Frame<B>, current_tab: &Tabs, input: &str, area: Rect)
+where
+    B: Backend,
+{
+    let block = match current_tab {
+        Tabs::ThreadInput => Block::default()
+            .borders(Borders::ALL)
+            .title("Reply in thread")
+            .border_type(BorderType::Thick),
+        _ => Block::default()
+            .borders(Borders::ALL)
+            .title("Reply in thread"),
+    };
+    f.render_widget(Paragraph::new(input.to_string()).block(block), area);
+    if *current_tab == Tabs::ThreadInput {
+        f.set_cursor(area.x + input.width() as u16 + 1, area.y + 1);
+    }
+}
+
+/// Renders the bodies of messages which are not rendered at the given width yet.
+/// Highlighting code blocks is too slow to do it again on every draw.
+/// # Arguments
+/// * `messages` - The messages.
+/// * `width` - The width the bodies are wrapped at.
+fn render_bodies(messages: &mut [TimelineItem], width: usize) {
+    for m in messages.iter_mut() {
+        match &m.rendered {
+            Some((rendered_width, _)) if *rendered_width == width => {}
+            _ => m.rendered = Some((width, message_body(m, width))),
+        }
+    }
+}
+
+/// Renders the body of a message, styled by its message type
+/// # Arguments
+/// * `m` - The message.
+/// * `width` - The width the body is wrapped at.
+fn message_body(m: &TimelineItem, width: usize) -> Text<'static> {
+    let emote = matches!(m.msgtype(), Some(MessageType::Emote(_)));
+    let mut body = match (m.formatted_body(), emote) {
+        (Some(formatted), _) => html::render(formatted, width),
+        (None, true) => highlight::render_plain(&format!("* {} {}", m.sender_name, m.body), width),
+        (None, false) => highlight::render_plain(&m.body, width),
+    };
+    match m.msgtype() {
+        Some(MessageType::Emote(_)) if m.formatted_body().is_some() => {
+            if let Some(first) = body.lines.first_mut() {
+                first
+                    .0
+                    .insert(0, Span::raw(format!("* {} ", m.sender_name)));
+            }
+        }
+        Some(MessageType::Notice(_)) => body.patch_style(Style::default().fg(Color::DarkGray)),
+        Some(MessageType::ServerNotice(_)) => body.patch_style(
+            Style::default()
+                .fg(Color::Yellow)
+                .add_modifier(Modifier::BOLD),
+        ),
+        _ => {}
+    }
+    body
+}
+
+/// Renders a message with its header, the quoted reply, the body,
+/// the reactions and the number of replies in its thread
+/// # Arguments
+/// * `m` - The message.
+/// * `room` - The room of the message, used to look up replied and thread messages.
+/// * `timestamp_format` - The strftime format of message timestamps.
+/// * `user_id` - The user id of the own user, whose reactions are highlighted.
+/// * `width` - The width the body is wrapped at.
+fn message_text(
+    m: &TimelineItem,
+    room: &Room,
+    timestamp_format: &str,
+    user_id: &str,
+    width: usize,
+) -> Text<'static> {
+    // Room changes are shown as a single dimmed line
+    if matches!(m.content, TimelineContent::State) {
+        let mut line = vec![Span::styled(
+            format!("{} ∙ {}", m.timestamp.format(timestamp_format), m.body),
+            Style::default().fg(Color::DarkGray),
+        )];
+        if let Some(readers) = readers_span(m, room, user_id) {
+            line.push(readers);
+        }
+        return Text::from(Spans::from(line));
+    }
+    let mut header = vec![Span::styled(
+        format!("{}:{}", m.timestamp.format(timestamp_format), m.sender_name),
+        Style::default().fg(Color::Green),
+    )];
+    match m.send_state {
+        SendState::Sending => header.push(Span::styled(
+            " (sending...)",
+            Style::default().fg(Color::DarkGray),
+        )),
+        SendState::Failed => header.push(Span::styled(
+            " (failed to send)",
+            Style::default().fg(Color::Red),
+        )),
+        SendState::Sent => {}
+    }
+    if m.edited {
+        header.push(Span::styled(
+            " (edited)",
+            Style::default().fg(Color::DarkGray),
+        ));
+    }
+    match m.msgtype() {
+        Some(MessageType::Notice(_)) => {
+            header.push(Span::styled(" [bot]", Style::default().fg(Color::DarkGray)))
+        }
+        Some(MessageType::ServerNotice(_)) => header.push(Span::styled(
+            " [server notice]",
+            Style::default()
+                .fg(Color::Yellow)
+                .add_modifier(Modifier::BOLD),
+        )),
+        _ => {}
+    }
+    if let Some(readers) = readers_span(m, room, user_id) {
+        header.push(readers);
+    }
+    let mut text = Text::from(Spans::from(header));
+    if let Some(event_id) = &m.in_reply_to {
+        let quote = match room.find_message(event_id) {
+            Some(original) => format!("│ {}: {}", original.sender_name, original.excerpt()),
+            None => "│ In reply to an older message".to_string(),
+        };
+        text.extend(Text::styled(quote, Style::default().fg(Color::DarkGray)));
+    }
+    let body = match &m.rendered {
+        Some((rendered_width, body)) if *rendered_width == width => body.clone(),
+        _ => message_body(m, width),
+    };
+    text.extend(body);
+    if !m.reactions.is_empty() {
+        let mut reactions = Vec::new();
+        for reaction in &m.reactions {
+            let style = match reaction.event_id_of(user_id) {
+                Some(_) => Style::default().fg(Color::Cyan),
+                None => Style::default(),
+            };
+            reactions.push(Span::styled(
+                format!("{} {}", reaction.key, reaction.senders.len()),
+                style,
+            ));
+            reactions.push(Span::raw("  "));
+        }
+        text.extend(Text::from(Spans::from(reactions)));
+    }
+    if let Some(event_id) = &m.event_id {
+        let replies = room.thread_replies(event_id);
+        if m.thread_root.is_none() && replies > 0 {
+            let summary = match replies {
+                1 => "└ 1 reply in thread".to_string(),
+                n => format!("└ {} replies in thread", n),
+            };
+            text.extend(Text::styled(summary, Style::default().fg(Color::Blue)));
+        }
+    }
+    text
+}
+
+/// Returns the initials of the members whose last read message is the given message
+/// # Arguments
+/// * `m` - The message.
+/// * `room` - The room of the message.
+/// * `user_id` - The user id of the own user, who is left out.
+fn readers_span(m: &TimelineItem, room: &Room, user_id: &str) -> Option<Span<'static>> {
+    let mut readers: Vec<String> = room
+        .receipts
+        .iter()
+        .filter(|(reader, event_id)| *reader != user_id && m.event_id.as_ref() == Some(*event_id))
+        .map(|(reader, _)| initial(&room.members.display_name(reader)))
+        .collect();
+    if readers.is_empty() {
+        return None;
+    }
+    readers.sort();
+    let mut read_by = readers
+        .iter()
+        .take(MAX_READERS)
+        .map(|reader| format!("[{}]", reader))
+        .collect::<Vec<String>>()
+        .join("");
+    if readers.len() > MAX_READERS {
+        read_by.push_str(&format!("+{}", readers.len() - MAX_READERS));
+    }
+    Some(Span::styled(
+        format!("  {}", read_by),
+        Style::default().fg(Color::Magenta),
+    ))
+}
+
+/// Returns the first letter or digit of a name in upper case, skipping the '@' of user ids
+/// # Arguments
+/// * `name` - The display name or user id
+fn initial(name: &str) -> String {
+    match name.chars().find(|c| c.is_alphanumeric()) {
+        Some(c) => c.to_uppercase().to_string(),
+        None => "?".to_string(),
+    }
+}
+
+/// Draws the banner of the message the input replies to, edits or deletes
+/// # Arguments
+/// * `f` - The frame to draw on.
+/// * `label` - What is done with the message.
+/// * `message` - The target message of the input.
+/// * `hint` - Additional help shown after the message.
+/// * `area` - The area to draw on.
+fn draw_input_banner<B>(
+    f: &mut Frame<B>,
+    label: &str,
+    message: &TimelineItem,
+    hint: Option<String>,
+    area: Rect,
+) where
+    B: Backend,
+{
+    let mut spans = vec![
+        Span::styled(
+            format!(" {} {}: ", label, message.sender_name),
+            Style::default().fg(Color::Green),
+        ),
+        Span::raw(message.excerpt()),
+    ];
+    if let Some(hint) = hint {
+        spans.push(Span::styled(hint, Style::default().fg(Color::DarkGray)));
+    }
+    f.render_widget(Paragraph::new(Spans::from(spans)), area);
 }
 
 /// Draws the room widget
@@ -278,8 +1068,20 @@ where
         .iter()
         .enumerate()
         .map(|(_i, m)| {
-            let content = vec![Spans::from(Span::raw(m.name.to_string()))];
-            ListItem::new(content)
+            let mut content = vec![Span::raw(m.name.to_string())];
+            if m.notifications > 0 {
+                content.push(Span::raw(format!(" ({})", m.notifications)));
+            }
+            if m.highlights > 0 {
+                content.push(Span::raw(format!(" @{}", m.highlights)));
+            }
+            // Bold for unread messages, colored for mentions
+            let style = match (m.notifications, m.highlights) {
+                (_, h) if h > 0 => Style::default().fg(Color::Red).add_modifier(Modifier::BOLD),
+                (n, _) if n > 0 => Style::default().add_modifier(Modifier::BOLD),
+                _ => Style::default(),
+            };
+            ListItem::new(Spans::from(content)).style(style)
         })
         .collect();
     let block_rooms = match app.current_tab {
@@ -298,12 +1100,25 @@ where
     //If room is selected render Member list
     match app.rooms.state.selected() {
         Some(i) => {
+            let member_list_height = app.config.ui.member_list_height;
             let chunks = Layout::default()
                 .direction(Direction::Vertical)
-                .constraints([Constraint::Percentage(70), Constraint::Percentage(30)].as_ref())
+                .constraints(
+                    [
+                        Constraint::Percentage(100 - member_list_height),
+                        Constraint::Percentage(member_list_height),
+                    ]
+                    .as_ref(),
+                )
                 .split(area);
             f.render_stateful_widget(rooms, chunks[0], &mut app.rooms.state);
-            draw_member_tab(f, &app.current_tab, &mut app.rooms.rooms[i], chunks[1]);
+            draw_member_tab(
+                f,
+                &app.current_tab,
+                &app.verified_users,
+                &mut app.rooms.rooms[i],
+                chunks[1],
+            );
         }
         None => {
             f.render_stateful_widget(rooms, area, &mut app.rooms.state);
@@ -312,12 +1127,19 @@ where
 }
 
 /// Draws the member widget
+/// Verified members are marked with a green check mark.
 /// # Arguments
 /// * `f` - The frame to draw on.
 /// * `current_tab` - The current tab.
+/// * `verified_users` - The users whose identity is verified.
 /// * `area` - The area to draw on.
-fn draw_member_tab<B>(f: &mut Frame<B>, current_tab: &Tabs, room: &mut Room, area: Rect)
-where
+fn draw_member_tab<B>(
+    f: &mut Frame<B>,
+    current_tab: &Tabs,
+    verified_users: &HashSet<String>,
+    room: &mut Room,
+    area: Rect,
+) where
     B: Backend,
 {
     let members: Vec<ListItem> = room
@@ -326,7 +1148,12 @@ where
         .iter()
         .enumerate()
         .map(|(_i, m)| {
-            let content = vec![Spans::from(Span::raw(m.0.to_string()))];
+            let marker = if verified_users.contains(&m.1) {
+                Span::styled("✔ ", Style::default().fg(Color::Green))
+            } else {
+                Span::styled("? ", Style::default().fg(Color::DarkGray))
+            };
+            let content = vec![Spans::from(vec![marker, Span::raw(m.0.to_string())])];
             ListItem::new(content)
         })
         .collect();
@@ -354,25 +1181,99 @@ fn draw_input_tab<B>(f: &mut Frame<B>, app: &mut App, area: Rect)
 where
     B: Backend,
 {
+    // Show the result of the last command next to the title
+    let title = match &app.status {
+        Some(status) => format!("Input | {}", status),
+        None => "Input".to_string(),
+    };
     let block = match app.current_tab {
         Tabs::Input => Block::default()
             .borders(Borders::ALL)
-            .title("Input")
+            .title(title)
             .border_type(BorderType::Thick),
-        _ => Block::default().borders(Borders::ALL).title("Input"),
+        _ => Block::default().borders(Borders::ALL).title(title),
     };
 
-    let input = Paragraph::new(app.input.as_ref())
-        .style(Style::default())
-        .block(block);
+    let text = mask_secrets(&app.input);
+    let text_width = text.width();
+    let input = Paragraph::new(text).style(Style::default()).block(block);
     f.render_widget(input, area);
     if app.current_tab == Tabs::Input {
         // Make the cursor visible and ask tui-rs to put it at the specified coordinates after rendering
         f.set_cursor(
             // Put cursor past the end of the input text
-            area.x + app.input.width() as u16 + 1,
+            area.x + text_width as u16 + 1,
             // Move one line down, from the border to the input line
             area.y + 1,
         );
     }
 }
+
+/// Hides passwords and passphrases given to commands
+/// # Arguments
+/// * `input` - The text of the input widget.
+/// # Returns
+/// * `String` - The text with secret arguments replaced by '*'
+fn mask_secrets(input: &str) -> String {
+    for (command, visible_args) in SECRET_COMMANDS {
+        if let Some(args) = input.strip_prefix(command) {
+            let mut masked = command.to_string();
+            let mut arg = 0;
+            let mut in_arg = false;
+            for c in args.chars() {
+                if c == ' ' {
+                    in_arg = false;
+                    masked.push(c);
+                    continue;
+                }
+                if !in_arg {
+                    in_arg = true;
+                    arg += 1;
+                }
+                masked.push(if arg > visible_args { '*' } else { c });
+            }
+            return masked;
+        }
+    }
+    input.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn passwords_are_masked() {
+        assert_eq!(
+            mask_secrets("/cross-signing hunter2"),
+            "/cross-signing *******"
+        );
+        assert_eq!(
+            mask_secrets("/backup restore EsTc 1234"),
+            "/backup restore **** ****"
+        );
+        assert_eq!(
+            mask_secrets("/backup enable my pass"),
+            "/backup enable ** ****"
+        );
+    }
+
+    #[test]
+    fn file_of_keys_command_is_shown() {
+        assert_eq!(
+            mask_secrets("/keys export ~/keys.txt secret words"),
+            "/keys export ~/keys.txt ****** *****"
+        );
+        assert_eq!(
+            mask_secrets("/keys import keys.txt pass"),
+            "/keys import keys.txt ****"
+        );
+    }
+
+    #[test]
+    fn other_input_is_not_masked() {
+        assert_eq!(mask_secrets("hello world"), "hello world");
+        assert_eq!(mask_secrets("/keys export"), "/keys export");
+        assert_eq!(mask_secrets("/me waves"), "/me waves");
+    }
+}